@@ -1,6 +1,11 @@
 -- Bind static classes from java
 StandardCharsets = luajava.bindClass("java.nio.charset.StandardCharsets")
 
+-- Maps metadata keys of the matched entries to Taxon features, set with the "metadata_features"
+-- parameter, i.e. "rank=taxonRank,kingdom=kingdom". Unmapped metadata is added as comments.
+-- Set anew by every "serialize" call, so the mappings of a document only apply to its results.
+metadata_features = {}
+
 -- This "serialize" function is called to transform the CAS object into an stream that is sent to the annotator
 -- Inputs:
 --  - inputCas: The actual CAS object to serialize
@@ -9,35 +14,64 @@ StandardCharsets = luajava.bindClass("java.nio.charset.StandardCharsets")
 function serialize(inputCas, outputStream, parameters)
     -- Get data from CAS
     local doc_text = inputCas:getDocumentText();
+    metadata_features = {}
+    -- CAS offsets count UTF-16 code units, not characters
+    local request = {
+        text = doc_text,
+        offset_unit = "utf16"
+    }
+    -- Optional parameters are only sent if present
     if parameters then
-        local max_len = parameters["max_len"]
-        local result_selection = parameters["result_selection"]
-        -- Encode data as JSON object and write to stream
-        if max_len and result_selection then
-            outputStream:write(json.encode({
-                text = doc_text,
-                max_len = max_len,
-                result_selection = result_selection,
-            }))
-            return
-        elseif max_len then
-            outputStream:write(json.encode({
-                text = doc_text,
-                max_len = max_len,
-            }))
-            return
-        elseif result_selection then
-            outputStream:write(json.encode({
-                text = doc_text,
-                result_selection = result_selection,
-            }))
-            return
+        request.max_len = parameters["max_len"]
+        request.result_selection = parameters["result_selection"]
+        request.license_abbreviations = parameters["license_abbreviations"] == "true" or nil
+        request.min_score = parameters["min_score"]
+        -- Rejected by the server if above its configured max_gap
+        request.max_gap = parameters["max_gap"]
+        request.offset_unit = parameters["offset_unit"] or request.offset_unit
+        if parameters["metadata_features"] then
+            for key, feature in string.gmatch(parameters["metadata_features"], "([^,=]+)=([^,]+)") do
+                metadata_features[key] = feature
+            end
         end
     end
     -- Encode data as JSON object and write to stream
-    outputStream:write(json.encode({
-        text = doc_text
-    }))
+    outputStream:write(json.encode(request))
+end
+
+-- Attach a key-value comment to an annotation, if the value is present (not just separators)
+function add_comment(inputCas, reference, key, value)
+    if value and value:match("[^%s|]") then
+        local comment = luajava.newInstance("org.texttechnologylab.annotation.AnnotationComment", inputCas)
+        comment:setReference(reference)
+        comment:setKey(key)
+        comment:setValue(value)
+        comment:addToIndexes()
+    end
+end
+
+-- Collect the distinct metadata values of all matches of a result per key, in order of appearance
+-- The metadata holds a list per match group, with a list of provenances per match of the group
+function collect_metadata(match_metadata)
+    local keys, values, seen = {}, {}, {}
+    for _, group in ipairs(match_metadata or {}) do
+        for _, provenances in ipairs(group) do
+            for _, entry in ipairs(provenances) do
+                for key, value in pairs(entry) do
+                    if not values[key] then
+                        table.insert(keys, key)
+                        values[key] = {}
+                        seen[key] = {}
+                    end
+                    if not seen[key][value] then
+                        seen[key][value] = true
+                        table.insert(values[key], value)
+                    end
+                end
+            end
+        end
+    end
+    return keys, values
 end
 
 -- This "deserialize" function is called on receiving the results from the annotator that have to be transformed into a CAS object
@@ -58,7 +92,36 @@ function deserialize(inputCas, inputStream)
         taxon:setIdentifier(match["match_labels"])
         taxon:setBegin(match["begin"])
         taxon:setEnd(match["end"])
+
+        -- Map the metadata of the matched entries onto Taxon features, or add it as comments
+        local keys, values = collect_metadata(match["match_metadata"])
+        local unmapped = {}
+        for _, key in ipairs(keys) do
+            local value = table.concat(values[key], " | ")
+            local feature = metadata_features[key] and taxon:getType():getFeatureByBaseName(metadata_features[key])
+            if feature then
+                taxon:setFeatureValueFromString(feature, value)
+            else
+                unmapped[key] = value
+            end
+        end
         taxon:addToIndexes()
+        for _, key in ipairs(keys) do
+            add_comment(inputCas, taxon, key, unmapped[key])
+        end
+
+        -- Record which corpora and source rows produced the match
+        add_comment(inputCas, taxon, "corpus", match["match_corpora"])
+        add_comment(inputCas, taxon, "source", match["match_sources"])
+        add_comment(inputCas, taxon, "score", match["match_scores"])
+        add_comment(inputCas, taxon, "skipped", match["match_skipped"])
+        add_comment(inputCas, taxon, "gaps", match["match_gaps"])
+        -- Record which matches were found by a synonym rather than the primary name
+        if match["match_synonyms"] and match["match_synonyms"]:find("true") then
+            add_comment(inputCas, taxon, "synonym", match["match_synonyms"])
+        end
+        -- Record the earlier full mention that licensed an abbreviation
+        add_comment(inputCas, taxon, "licensed_by", match["match_licensed_by"])
     end
 
 end
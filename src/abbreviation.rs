@@ -0,0 +1,157 @@
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+
+/// Per-corpus settings for generating abbreviated entries, in addition to `abbrv_max_index` and
+/// `abbrv_min_suffix_length`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AbbreviationOptions {
+    /// The maximum number of segments abbreviated at once, i.e. 2 for "P. p. puffinus". At least
+    /// one segment is always kept in full.
+    pub max_segments: usize,
+    /// The lengths (in characters) of the prefixes that segments are abbreviated to, i.e. `[1, 4]`
+    /// for both "P. puffinus" and "Puff. puffinus". Segments are only abbreviated to prefixes
+    /// shorter than themselves.
+    pub prefix_lengths: Vec<usize>,
+    /// If true, abbreviated segments are followed by a period in the key. This requires a
+    /// tokenizer that keeps punctuation, see `PreTokenizerKind::Bert`.
+    pub period: bool,
+}
+
+impl Default for AbbreviationOptions {
+    fn default() -> Self {
+        AbbreviationOptions {
+            max_segments: 1,
+            prefix_lengths: vec![1],
+            period: false,
+        }
+    }
+}
+
+/// All abbreviated variants of the given segments. Each abbreviated prefix is turned into tokens
+/// with `tokenize`, which receives the prefix with its trailing period if `options.period` is set.
+pub(crate) fn abbreviate<F>(
+    segments: &[String],
+    abbrv_max_index: i32,
+    abbrv_min_suffix_length: i32,
+    options: &AbbreviationOptions,
+    tokenize: F,
+) -> Vec<Vec<String>>
+where
+    F: Fn(&str) -> Vec<String>,
+{
+    if segments.len() < 2 {
+        return Vec::new();
+    }
+    // set the maximum abbreviated segment index to the last segment
+    // UNLESS the user has specified a maximum index (abbrv_max_index > 0)
+    let max_index = segments.len() - 1;
+    let max_index = if abbrv_max_index < 0 || abbrv_max_index > (max_index as i32) {
+        max_index
+    } else {
+        abbrv_max_index as usize
+    };
+
+    let mut variants = Vec::new();
+    for size in 1..=options.max_segments.min(segments.len() - 1) {
+        for indices in (0..=max_index).combinations(size) {
+            // check if the remaining segments after the last abbreviated segment are long enough
+            // i.e., "Thing A" -> "T A"
+            let last = indices[indices.len() - 1];
+            let suffix_length: usize = segments[(last + 1)..].iter().map(String::len).sum();
+            if abbrv_min_suffix_length > 0 && suffix_length < (abbrv_min_suffix_length as usize) {
+                continue;
+            }
+
+            let prefixes = indices.iter().map(|idx| {
+                let length = segments[*idx].chars().count();
+                options
+                    .prefix_lengths
+                    .iter()
+                    .filter(move |prefix_length| **prefix_length > 0 && **prefix_length < length)
+                    .map(move |prefix_length| (*idx, *prefix_length))
+            });
+            for abbreviated in prefixes.multi_cartesian_product() {
+                let mut variant: Vec<String> = Vec::with_capacity(segments.len() + size);
+                let mut abbreviated = abbreviated.into_iter().peekable();
+                for (idx, segment) in segments.iter().enumerate() {
+                    match abbreviated.next_if(|(abbreviated_idx, _)| *abbreviated_idx == idx) {
+                        Some((_, prefix_length)) => {
+                            let mut prefix: String = segment.chars().take(prefix_length).collect();
+                            if options.period {
+                                prefix.push('.');
+                            }
+                            variant.extend(tokenize(&prefix));
+                        }
+                        None => variant.push(segment.clone()),
+                    }
+                }
+                variants.push(variant);
+            }
+        }
+    }
+    variants
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn split(prefix: &str) -> Vec<String> {
+        match prefix.strip_suffix('.') {
+            Some(prefix) => vec![prefix.to_string(), ".".to_string()],
+            None => vec![prefix.to_string()],
+        }
+    }
+
+    fn keys(variants: Vec<Vec<String>>) -> Vec<String> {
+        variants
+            .into_iter()
+            .map(|variant| variant.join(" "))
+            .collect()
+    }
+
+    #[test]
+    fn test_abbreviate() {
+        let segments: Vec<String> = vec![
+            "Puffinus".to_string(),
+            "puffinus".to_string(),
+            "puffinus".to_string(),
+        ];
+        let options = AbbreviationOptions::default();
+        assert_eq!(
+            keys(abbreviate(&segments, 1, 3, &options, split)),
+            vec!["P puffinus puffinus", "Puffinus p puffinus"]
+        );
+
+        let options = AbbreviationOptions {
+            max_segments: 2,
+            prefix_lengths: vec![1, 4],
+            period: true,
+        };
+        assert_eq!(
+            keys(abbreviate(&segments, 1, 3, &options, split)),
+            vec![
+                "P . puffinus puffinus",
+                "Puff . puffinus puffinus",
+                "Puffinus p . puffinus",
+                "Puffinus puff . puffinus",
+                "P . p . puffinus",
+                "P . puff . puffinus",
+                "Puff . p . puffinus",
+                "Puff . puff . puffinus",
+            ]
+        );
+
+        // the suffix after the last abbreviated segment is too short
+        assert_eq!(
+            keys(abbreviate(&segments[1..], -1, 9, &options, split)),
+            Vec::<String>::new()
+        );
+        // segments are never abbreviated to themselves
+        let segments: Vec<String> = vec!["Sula".to_string(), "sula".to_string()];
+        assert_eq!(
+            keys(abbreviate(&segments, 0, 0, &options, split)),
+            vec!["S . sula"]
+        );
+    }
+}
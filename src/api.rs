@@ -1,24 +1,159 @@
 use std::borrow::Cow;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::Infallible;
+use std::num::NonZeroUsize;
+use std::pin::Pin;
 use std::sync::Arc;
+use std::task::Poll;
 
+use anyhow::{anyhow, bail, Context};
+use futures_util::StreamExt;
+use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 
 use actix_files::NamedFile;
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::http::header;
 use actix_web::web;
-use actix_web::HttpResponse;
+use actix_web::web::{Bytes, BytesMut};
 use actix_web::Result;
+use actix_web::{HttpRequest, HttpResponse};
+use tokio::sync::{mpsc, Semaphore};
 
-use crate::tree::ResultSelection;
-use crate::util::parse_optional;
+use crate::tree::{
+    CorpusFilter, HashMapSearchTree, MatchType, Provenance, ResultSelection, SearchMatch,
+    SearchOptions, SearchResult,
+};
+use crate::util::{parse_optional, OffsetUnit};
 use crate::AppState;
 
+/// The number of finished documents of a batch that are buffered until they are sent.
+const BATCH_CHANNEL_CAPACITY: usize = 64;
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ProcessRequest<'r> {
     pub text: Cow<'r, str>,
+    #[serde(flatten)]
+    pub options: ProcessOptions,
+}
+
+/// The options of a process request, see `SearchOptions`.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ProcessOptions {
     pub max_len: Option<String>,
     pub result_selection: Option<ResultSelection>,
+    /// If given, only return matches from these corpora.
+    pub include_corpora: Option<HashSet<String>>,
+    /// If given, never return matches from these corpora.
+    pub exclude_corpora: Option<HashSet<String>>,
+    /// If true, only return abbreviations that are licensed by an earlier full mention.
+    pub license_abbreviations: Option<bool>,
+    /// If given, only return matches with at least this score.
+    pub min_score: Option<String>,
+    /// If given, also return gapped matches with up to this many additional tokens.
+    pub max_gap: Option<String>,
+    /// The unit of the returned offsets: `char` (default), `utf16` or `byte`.
+    pub offset_unit: Option<OffsetUnit>,
+}
+
+impl ProcessOptions {
+    #[must_use]
+    pub fn corpus_filter(&self) -> Option<CorpusFilter> {
+        if self.include_corpora.is_none() && self.exclude_corpora.is_none() {
+            return None;
+        }
+        Some(CorpusFilter {
+            include: self.include_corpora.clone(),
+            exclude: self.exclude_corpora.clone(),
+        })
+    }
+
+    /// Rejects options that the tree does not allow, i.e. a `max_gap` above
+    /// `HashMapSearchTree::max_gap`.
+    pub fn validate(&self, tree: &HashMapSearchTree) -> anyhow::Result<()> {
+        if let Some(max_gap) = parse_optional::<usize>(&self.max_gap) {
+            if max_gap > tree.max_gap() {
+                bail!("max_gap must be at most {}, got {max_gap}", tree.max_gap());
+            }
+        }
+        Ok(())
+    }
+
+    #[must_use]
+    pub fn search_options(&self) -> SearchOptions {
+        SearchOptions {
+            max_len: parse_optional::<usize>(&self.max_len),
+            result_selection: self.result_selection.clone(),
+            corpus_filter: self.corpus_filter(),
+            license_abbreviations: self.license_abbreviations.unwrap_or(false),
+            min_score: parse_optional::<f64>(&self.min_score),
+            max_gap: parse_optional::<usize>(&self.max_gap),
+            offset_unit: self.offset_unit.unwrap_or_default(),
+        }
+    }
+}
+
+/// A document of a batch. Batches are sent as a JSON array or as newline-delimited JSON.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Document {
+    /// Any JSON value that identifies the document in the results.
+    pub id: Value,
+    pub text: String,
+    pub options: Option<ProcessOptions>,
+}
+
+/// The results of a single document of a batch.
+#[derive(Debug, Clone, Serialize)]
+pub struct DocumentResult {
+    pub id: Value,
+    pub results: Vec<SpanV2>,
+}
+
+/// A document of a batch that could not be read or processed, reported in place of its results.
+#[derive(Debug, Clone, Serialize)]
+pub struct DocumentError {
+    /// The id of the document, if it could be read.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<Value>,
+    /// The 1-based line of the document in newline-delimited input.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line: Option<usize>,
+    pub error: String,
+}
+
+impl Document {
+    /// Processes the document with its own options, or with `defaults` if it has none.
+    pub fn process(
+        &self,
+        tree: &HashMapSearchTree,
+        defaults: &ProcessOptions,
+    ) -> anyhow::Result<DocumentResult> {
+        Ok(DocumentResult {
+            id: self.id.clone(),
+            results: process_v2(tree, &self.text, self.options.as_ref().unwrap_or(defaults))?,
+        })
+    }
+}
+
+/// Parses a batch of documents, given as a JSON array or as one JSON document per line.
+pub fn parse_documents(body: &[u8]) -> anyhow::Result<Vec<Document>> {
+    if body.trim_ascii_start().starts_with(b"[") {
+        return serde_json::from_slice(body).context("Invalid JSON array of documents");
+    }
+    body.split(|byte| *byte == b'\n')
+        .enumerate()
+        .filter_map(|(idx, line)| parse_line(line, idx + 1))
+        .collect()
+}
+
+/// Parses a line of newline-delimited documents, given its 1-based line number. Returns None for
+/// blank lines.
+pub fn parse_line(line: &[u8], line_number: usize) -> Option<anyhow::Result<Document>> {
+    (!line.trim_ascii().is_empty()).then(|| {
+        serde_json::from_slice(line)
+            .with_context(|| format!("Invalid document in line {line_number}"))
+    })
 }
 
 pub async fn v1_communication_layer() -> Result<NamedFile> {
@@ -29,38 +164,740 @@ pub async fn v1_process(
     request: web::Json<ProcessRequest<'_>>,
     state: web::Data<Arc<AppState>>,
 ) -> HttpResponse {
-    let results = state.get_ref().tree.search(
-        &request.text,
-        parse_optional::<usize>(&request.max_len),
-        Option::from(&request.result_selection),
-    );
+    let tree = state.tree();
+    if let Err(e) = request.options.validate(&tree) {
+        return HttpResponse::BadRequest().body(format!("{e:#}"));
+    }
+    let results = tree.search(&request.text, &request.options.search_options());
     let results: Vec<Value> = results
         .into_iter()
-        .map(|(string, mtches, begin, end)| {
-            let mut value: HashMap<(String, String), Vec<String>> = HashMap::new();
-            for mtch in mtches {
+        .map(|result| {
+            let mut value: HashMap<(String, String), Vec<SearchMatch>> = HashMap::new();
+            for mtch in result.matches {
                 value
                     .entry((mtch.match_string.to_string(), mtch.match_type.to_string()))
-                    .and_modify(|e| e.push(mtch.match_label.to_string()))
-                    .or_insert_with(|| vec![mtch.match_label.to_string()]);
+                    .or_default()
+                    .push(mtch);
             }
 
-            let ((match_strings, match_types), match_labels): (
-                (Vec<String>, Vec<String>),
-                Vec<String>,
-            ) = value
-                .into_iter()
-                .map(|((s, t), l)| ((s, t), l.join(" ")))
-                .unzip();
+            let mut match_strings = Vec::new();
+            let mut match_types = Vec::new();
+            let mut match_labels = Vec::new();
+            let mut match_corpora = Vec::new();
+            let mut match_sources = Vec::new();
+            let mut match_licensed_by = Vec::new();
+            let mut match_scores = Vec::new();
+            let mut match_skipped = Vec::new();
+            let mut match_gaps = Vec::new();
+            let mut match_metadata = Vec::new();
+            let mut match_synonyms = Vec::new();
+            for ((match_string, match_type), mtches) in value {
+                match_strings.push(match_string);
+                match_types.push(match_type);
+                match_labels.push(
+                    mtches
+                        .iter()
+                        .map(|mtch| mtch.match_label.as_str())
+                        .join(" "),
+                );
+                match_corpora.push(mtches.iter().map(|mtch| mtch.corpora().join(",")).join(" "));
+                match_sources.push(
+                    mtches
+                        .iter()
+                        .map(|mtch| {
+                            mtch.provenances
+                                .iter()
+                                .map(|provenance| {
+                                    format!("{}:{}", provenance.source, provenance.row)
+                                })
+                                .join(",")
+                        })
+                        .join(" "),
+                );
+                match_scores.push(
+                    mtches
+                        .iter()
+                        .map(|mtch| format!("{:.4}", mtch.score))
+                        .join(" "),
+                );
+                match_skipped.push(
+                    mtches
+                        .iter()
+                        .map(|mtch| mtch.skipped_tokens.join(","))
+                        .join(" "),
+                );
+                match_gaps.push(
+                    mtches
+                        .iter()
+                        .map(|mtch| {
+                            mtch.gaps
+                                .iter()
+                                .map(|(begin, end)| format!("{begin}:{end}"))
+                                .join(",")
+                        })
+                        .join(" "),
+                );
+                match_synonyms.push(
+                    mtches
+                        .iter()
+                        .map(|mtch| mtch.is_synonym().to_string())
+                        .join(" "),
+                );
+                // one list per match, in the order of its labels
+                match_metadata.push(
+                    mtches
+                        .iter()
+                        .map(|mtch| {
+                            mtch.provenances
+                                .iter()
+                                .map(|provenance| {
+                                    provenance
+                                        .metadata
+                                        .iter()
+                                        .map(|(key, value)| (key.to_string(), json!(value)))
+                                        .collect::<serde_json::Map<String, Value>>()
+                                })
+                                .collect::<Vec<_>>()
+                        })
+                        .collect::<Vec<_>>(),
+                );
+                match_licensed_by.push(
+                    mtches
+                        .iter()
+                        .map(|mtch| {
+                            mtch.licensed_by
+                                .map_or_else(String::new, |(begin, end)| format!("{begin}:{end}"))
+                        })
+                        .join(" "),
+                );
+            }
             json!({
-                "string": string,
+                "string": result.string,
                 "match_labels": match_labels.join(" | "),
                 "match_types": match_types.join(" | "),
                 "match_strings": match_strings.join(" | "),
-                "begin": begin,
-                "end": end,
+                "match_corpora": match_corpora.join(" | "),
+                "match_sources": match_sources.join(" | "),
+                "match_licensed_by": match_licensed_by.join(" | "),
+                "match_scores": match_scores.join(" | "),
+                "match_skipped": match_skipped.join(" | "),
+                "match_gaps": match_gaps.join(" | "),
+                "match_metadata": match_metadata,
+                "match_synonyms": match_synonyms.join(" | "),
+                "begin": result.begin,
+                "end": result.end,
+                "nested": result.nested,
             })
         })
         .collect::<Vec<Value>>();
     HttpResponse::Ok().json(results)
 }
+
+/// A match as returned by `/v2/process`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MatchV2 {
+    pub label: String,
+    #[serde(rename = "type")]
+    pub match_type: MatchType,
+    /// The search term of the matched entry.
+    pub entry: String,
+    /// The distinct corpora of the provenances, see `SearchMatch::corpora`.
+    pub corpora: Vec<String>,
+    /// The rows the entry was loaded from.
+    pub provenances: Vec<ProvenanceV2>,
+    pub score: f64,
+    pub distance: u32,
+    pub skipped: Vec<String>,
+    pub gaps: Vec<(usize, usize)>,
+    pub licensed_by: Option<(usize, usize)>,
+}
+
+/// A row a match was loaded from, as returned by `/v2/process`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ProvenanceV2 {
+    pub corpus: String,
+    /// The file and line the entry was read from, as `file:line`.
+    pub source: String,
+    pub synonym: bool,
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl From<&Provenance> for ProvenanceV2 {
+    fn from(provenance: &Provenance) -> Self {
+        ProvenanceV2 {
+            corpus: provenance.corpus.to_string(),
+            source: format!("{}:{}", provenance.source, provenance.row),
+            synonym: provenance.synonym,
+            metadata: provenance
+                .metadata
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+        }
+    }
+}
+
+impl From<&SearchMatch> for MatchV2 {
+    fn from(mtch: &SearchMatch) -> Self {
+        MatchV2 {
+            label: mtch.match_label.to_string(),
+            match_type: mtch.match_type.clone(),
+            entry: mtch.match_string.to_string(),
+            corpora: mtch.corpora().map(String::from).collect(),
+            provenances: mtch
+                .provenances
+                .iter()
+                .map(|provenance| ProvenanceV2::from(&**provenance))
+                .collect(),
+            score: mtch.score,
+            distance: mtch.distance,
+            skipped: mtch.skipped_tokens.clone(),
+            gaps: mtch.gaps.clone(),
+            licensed_by: mtch.licensed_by,
+        }
+    }
+}
+
+/// A span of the text and all of its matches as returned by `/v2/process`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SpanV2 {
+    pub begin: usize,
+    pub end: usize,
+    /// The text of the span as it occurs in the document.
+    pub text: String,
+    pub nested: bool,
+    pub matches: Vec<MatchV2>,
+}
+
+/// Converts search results with offsets in the given unit into spans. The matches of each span
+/// are sorted, so the output is deterministic.
+#[must_use]
+pub fn spans_v2(text: &str, offset_unit: OffsetUnit, results: Vec<SearchResult>) -> Vec<SpanV2> {
+    // maps the offsets of the results to byte offsets, both are character boundaries
+    let boundaries = offset_unit.boundaries(text);
+    let byte_boundaries = OffsetUnit::Byte.boundaries(text);
+    let byte_offset = |offset: usize| match boundaries.binary_search(&offset) {
+        Ok(idx) | Err(idx) => byte_boundaries[idx],
+    };
+    results
+        .into_iter()
+        .map(|mut result| {
+            result.matches.sort_by(|a, b| a.cmp_entry(b));
+            SpanV2 {
+                begin: result.begin,
+                end: result.end,
+                text: String::from(&text[byte_offset(result.begin)..byte_offset(result.end)]),
+                nested: result.nested,
+                matches: result.matches.iter().map(MatchV2::from).collect(),
+            }
+        })
+        .collect()
+}
+
+/// Searches the text and converts the results into spans, see `ProcessOptions::validate`.
+pub fn process_v2(
+    tree: &HashMapSearchTree,
+    text: &str,
+    options: &ProcessOptions,
+) -> anyhow::Result<Vec<SpanV2>> {
+    options.validate(tree)?;
+    let results = tree.search(text, &options.search_options());
+    Ok(spans_v2(
+        text,
+        options.offset_unit.unwrap_or_default(),
+        results,
+    ))
+}
+
+pub async fn v2_process(
+    request: web::Json<ProcessRequest<'_>>,
+    state: web::Data<Arc<AppState>>,
+) -> HttpResponse {
+    match process_v2(&state.tree(), &request.text, &request.options) {
+        Ok(spans) => HttpResponse::Ok().json(spans),
+        Err(e) => HttpResponse::BadRequest().body(format!("{e:#}")),
+    }
+}
+
+/// A response body of newline-delimited JSON, sent by the workers of a batch.
+struct LineStream(mpsc::Receiver<Bytes>);
+
+impl MessageBody for LineStream {
+    type Error = Infallible;
+
+    fn size(&self) -> BodySize {
+        BodySize::Stream
+    }
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<std::result::Result<Bytes, Self::Error>>> {
+        self.get_mut().0.poll_recv(cx).map(|line| line.map(Ok))
+    }
+}
+
+/// The limits of batches: the size limit of a document of a batch, i.e. of a line or of the
+/// whole JSON array, and the permits to process a document, which are shared by all batches so
+/// that concurrent batches do not process more documents at once than there are cores.
+#[derive(Debug, Clone)]
+pub struct BatchLimit {
+    pub size: usize,
+    parallelism: usize,
+    permits: Arc<Semaphore>,
+}
+
+impl BatchLimit {
+    #[must_use]
+    pub fn new(size: usize) -> Self {
+        let parallelism = std::thread::available_parallelism().map_or(1, NonZeroUsize::get);
+        BatchLimit {
+            size,
+            parallelism,
+            permits: Arc::new(Semaphore::new(parallelism)),
+        }
+    }
+}
+
+/// Processes the documents of a batch on the rayon pool and sends the result of each as a line
+/// of JSON. Documents hold a permit until their result is sent, so a slow client holds back
+/// reading the request instead of piling up results.
+struct BatchWorker {
+    tree: Arc<HashMapSearchTree>,
+    sender: mpsc::Sender<Bytes>,
+    permits: Arc<Semaphore>,
+}
+
+impl BatchWorker {
+    /// Waits for a slot in the channel and for a permit and processes the document in the
+    /// background, or reports why it could not be read. Returns false once the client has
+    /// disconnected.
+    async fn submit(&self, document: anyhow::Result<Document>, line: Option<usize>) -> bool {
+        // the slot is reserved first, so that the send never blocks a rayon worker and a slow
+        // client only holds back its own batch instead of the shared permits
+        let Ok(slot) = self.sender.clone().reserve_owned().await else {
+            return false;
+        };
+        let Ok(permit) = self.permits.clone().acquire_owned().await else {
+            return false;
+        };
+        let tree = self.tree.clone();
+        rayon::spawn(move || {
+            slot.send(batch_line(&tree, document, line));
+            drop(permit);
+        });
+        true
+    }
+}
+
+/// The result of a document of a batch as a line of JSON, or a `DocumentError`.
+fn batch_line(
+    tree: &HashMapSearchTree,
+    document: anyhow::Result<Document>,
+    line: Option<usize>,
+) -> Bytes {
+    let (id, json) = match document {
+        Ok(document) => {
+            let json = document
+                .process(tree, &ProcessOptions::default())
+                .and_then(|result| {
+                    serde_json::to_vec(&result).context("Failed to serialize the results")
+                });
+            (Some(document.id), json)
+        }
+        Err(e) => (None, Err(e)),
+    };
+    let mut json = json.unwrap_or_else(|e| {
+        let error = DocumentError {
+            id,
+            line,
+            error: format!("{e:#}"),
+        };
+        // a document error only holds JSON values and strings, which always serialize
+        serde_json::to_vec(&error).unwrap_or_default()
+    });
+    json.push(b'\n');
+    Bytes::from(json)
+}
+
+/// Processes a batch of documents in parallel and streams the result of each document as a line
+/// of JSON as soon as it is finished, so the results are not in the order of the documents.
+/// Newline-delimited documents are processed as they arrive, JSON arrays once they are read
+/// completely.
+pub async fn v2_batch(
+    mut payload: web::Payload,
+    limit: web::Data<BatchLimit>,
+    state: web::Data<Arc<AppState>>,
+) -> HttpResponse {
+    let limit = limit.get_ref().clone();
+    let mut buffer = BytesMut::new();
+    // the first non-whitespace byte tells a JSON array from newline-delimited JSON
+    while buffer.trim_ascii_start().is_empty() && buffer.len() <= limit.size {
+        match payload.next().await {
+            Some(Ok(chunk)) => buffer.extend_from_slice(&chunk),
+            Some(Err(e)) => return HttpResponse::BadRequest().body(e.to_string()),
+            None => break,
+        }
+    }
+    let is_array = buffer.trim_ascii_start().starts_with(b"[");
+    let mut documents = Vec::new();
+    if is_array {
+        while let Some(chunk) = payload.next().await {
+            match chunk {
+                Ok(chunk) if buffer.len() + chunk.len() <= limit.size => {
+                    buffer.extend_from_slice(&chunk);
+                }
+                Ok(_) => return HttpResponse::PayloadTooLarge().finish(),
+                Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+            }
+        }
+        documents = match parse_documents(&buffer) {
+            Ok(documents) => documents,
+            Err(e) => return HttpResponse::BadRequest().body(format!("{e:#}")),
+        };
+    }
+
+    // a single batch can use all permits
+    let (sender, receiver) = mpsc::channel(BATCH_CHANNEL_CAPACITY.max(limit.parallelism));
+    let worker = BatchWorker {
+        tree: state.tree(),
+        sender,
+        permits: limit.permits,
+    };
+    actix_web::rt::spawn(async move {
+        if is_array {
+            for document in documents {
+                if !worker.submit(Ok(document), None).await {
+                    return;
+                }
+            }
+            return;
+        }
+        let mut line_number = 0;
+        // the bytes of the buffer that are known to hold no newline
+        let mut scanned = 0;
+        loop {
+            while let Some(end) = buffer[scanned..].iter().position(|byte| *byte == b'\n') {
+                let line = buffer.split_to(scanned + end + 1);
+                scanned = 0;
+                line_number += 1;
+                if let Some(document) = parse_line(&line, line_number) {
+                    if !worker.submit(document, Some(line_number)).await {
+                        return;
+                    }
+                }
+            }
+            scanned = buffer.len();
+            if buffer.len() > limit.size {
+                let error = anyhow!("Line {} exceeds the size limit", line_number + 1);
+                worker.submit(Err(error), Some(line_number + 1)).await;
+                return;
+            }
+            match payload.next().await {
+                Some(Ok(chunk)) => buffer.extend_from_slice(&chunk),
+                Some(Err(e)) => {
+                    let error = anyhow!(e).context("Failed to read the batch");
+                    worker.submit(Err(error), None).await;
+                    return;
+                }
+                None => break,
+            }
+        }
+        // the last line may not end with a newline
+        if let Some(document) = parse_line(&buffer, line_number + 1) {
+            worker.submit(document, Some(line_number + 1)).await;
+        }
+    });
+    HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .body(LineStream(receiver))
+}
+
+/// Rejects admin requests that do not present the admin token as `Authorization: Bearer`, or
+/// that do not come from the loopback interface if no token is configured.
+fn authorize_admin(request: &HttpRequest, state: &AppState) -> Option<HttpResponse> {
+    let token = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let peer = request.peer_addr().map(|addr| addr.ip());
+    (!state.authorizes_admin(token, peer)).then(|| HttpResponse::Unauthorized().finish())
+}
+
+pub async fn admin_reload(request: HttpRequest, state: web::Data<Arc<AppState>>) -> HttpResponse {
+    if !state.has_admin_token() {
+        return HttpResponse::Forbidden()
+            .body("Reloading via /admin/reload requires an admin token, see --admin-token");
+    }
+    if let Some(response) = authorize_admin(&request, &state) {
+        return response;
+    }
+    if state.get_ref().reload() {
+        HttpResponse::Accepted().json(state.reload_status())
+    } else {
+        HttpResponse::Conflict().json(state.reload_status())
+    }
+}
+
+pub async fn admin_reload_status(
+    request: HttpRequest,
+    state: web::Data<Arc<AppState>>,
+) -> HttpResponse {
+    if let Some(response) = authorize_admin(&request, &state) {
+        return response;
+    }
+    HttpResponse::Ok().json(state.reload_status())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sula_tree;
+
+    #[test]
+    fn test_spans_v2() {
+        let mut tree = HashMapSearchTree::default();
+        tree.load(
+            vec![
+                ("Sula".to_string(), "uri:sula-b".to_string()),
+                ("Sula".to_string(), "uri:sula-a".to_string()),
+                ("Puffinus puffinus".to_string(), "uri:puffinus".to_string()),
+            ],
+            false,
+            0,
+            0,
+            true,
+            0,
+            3,
+        );
+        let text = "Die Ölsula… Sula und P. puffinus";
+        let spans = spans_v2(
+            text,
+            OffsetUnit::Char,
+            tree.search(text, &SearchOptions::default()),
+        );
+        let found: Vec<(&str, Vec<(&str, &MatchType)>)> = spans
+            .iter()
+            .map(|span| {
+                (
+                    span.text.as_str(),
+                    span.matches
+                        .iter()
+                        .map(|mtch| (mtch.label.as_str(), &mtch.match_type))
+                        .collect(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (
+                    "Sula",
+                    vec![
+                        ("uri:sula-a", &MatchType::Full),
+                        ("uri:sula-b", &MatchType::Full)
+                    ]
+                ),
+                (
+                    "P. puffinus",
+                    vec![("uri:puffinus", &MatchType::Abbreviated)]
+                ),
+            ]
+        );
+        assert_eq!(spans[1].matches[0].entry, "Puffinus puffinus");
+        assert_eq!((spans[0].begin, spans[0].end), (12, 16));
+
+        // the surface text is taken from the same offsets in other units
+        let options = SearchOptions {
+            offset_unit: OffsetUnit::Byte,
+            ..Default::default()
+        };
+        let spans = spans_v2(text, OffsetUnit::Byte, tree.search(text, &options));
+        assert_eq!((spans[0].begin, spans[0].end), (15, 19));
+        assert_eq!(spans[1].text, "P. puffinus");
+    }
+
+    #[test]
+    fn test_parse_documents() {
+        let ndjson = b"{\"id\": 1, \"text\": \"Sula\"}\n\n{\"id\": \"b\", \"text\": \"\", \"options\": {\"offset_unit\": \"utf16\"}}\n";
+        let documents = parse_documents(ndjson).unwrap();
+        assert_eq!(documents.len(), 2);
+        assert_eq!(documents[0].id, json!(1));
+        assert_eq!(
+            documents[1].options.as_ref().unwrap().offset_unit,
+            Some(OffsetUnit::Utf16)
+        );
+
+        let array = b" [{\"id\": 1, \"text\": \"Sula\"}, {\"id\": 2, \"text\": \"Sula\"}]";
+        assert_eq!(parse_documents(array).unwrap().len(), 2);
+
+        let error = parse_documents(b"{\"id\": 1, \"text\": \"Sula\"}\n{\"id\": 2}").unwrap_err();
+        assert_eq!(error.to_string(), "Invalid document in line 2");
+
+        let mut tree = sula_tree();
+        let result = documents[0]
+            .process(&tree, &ProcessOptions::default())
+            .unwrap();
+        assert_eq!(result.id, json!(1));
+        assert_eq!(result.results[0].matches[0].label, "uri:sula");
+
+        // gaps above the maximum of the tree are rejected
+        tree.set_max_gap(1);
+        let options = |max_gap: &str| ProcessOptions {
+            max_gap: Some(max_gap.to_string()),
+            ..Default::default()
+        };
+        assert!(documents[0].process(&tree, &options("1")).is_ok());
+        let error = documents[0].process(&tree, &options("2")).unwrap_err();
+        assert_eq!(error.to_string(), "max_gap must be at most 1, got 2");
+    }
+
+    #[actix_web::test]
+    async fn test_v1_metadata() {
+        use actix_web::{test, App};
+
+        // two labels of the same key with different metadata
+        let entry = |label: &str, rank: &str| {
+            let provenance = Provenance {
+                corpus: Arc::new(String::from("corpus")),
+                source: Arc::new(String::from("corpus.tsv")),
+                row: 1,
+                metadata: Arc::from(vec![(Arc::from("rank"), Arc::from(rank))]),
+                synonym: false,
+            };
+            (
+                String::from("Sula"),
+                String::from(label),
+                Some(Arc::new(provenance)),
+            )
+        };
+        let mut tree = HashMapSearchTree::default();
+        tree.load_with_provenance(
+            vec![entry("uri:genus", "GENUS"), entry("uri:species", "SPECIES")],
+            false,
+            0,
+            0,
+            false,
+            0,
+            3,
+        );
+        let state = Arc::new(AppState::new(tree, Default::default(), None));
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .route("/v1/process", web::post().to(v1_process)),
+        )
+        .await;
+        let request = test::TestRequest::post()
+            .uri("/v1/process")
+            .set_json(json!({"text": "Sula"}))
+            .to_request();
+        let results: Vec<Value> = test::call_and_read_body_json(&app, request).await;
+
+        // the metadata of each match lines up with its label
+        let labels: Vec<&str> = results[0]["match_labels"]
+            .as_str()
+            .unwrap()
+            .split(' ')
+            .collect();
+        let ranks: Vec<&Value> = results[0]["match_metadata"][0]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|provenances| &provenances[0]["rank"])
+            .collect();
+        assert_eq!(labels.len(), 2);
+        for (label, rank) in labels.iter().zip(ranks) {
+            let expected = if *label == "uri:genus" {
+                "GENUS"
+            } else {
+                "SPECIES"
+            };
+            assert_eq!(rank, expected);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_v2_batch() {
+        use actix_web::{test, App};
+
+        let tree = sula_tree();
+        let state = Arc::new(AppState::new(tree, Default::default(), None));
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .app_data(web::Data::new(BatchLimit::new(1024)))
+                .route("/v2/batch", web::post().to(v2_batch)),
+        )
+        .await;
+        let batch = |body: &'static str| {
+            test::TestRequest::post()
+                .uri("/v2/batch")
+                .set_payload(body)
+                .to_request()
+        };
+        // results arrive in the order they are finished
+        let lines = |body: Bytes| -> Vec<String> {
+            let mut lines: Vec<String> = String::from_utf8(body.to_vec())
+                .unwrap()
+                .lines()
+                .map(String::from)
+                .collect();
+            lines.sort();
+            lines
+        };
+
+        // malformed lines and rejected options are reported without ending the batch
+        let body = test::call_and_read_body(
+            &app,
+            batch("{\"id\": 1, \"text\": \"Sula\"}\nSula\n\n{\"id\": 2, \"text\": \"\", \"options\": {\"max_gap\": \"9\"}}"),
+        )
+        .await;
+        assert_eq!(
+            lines(body),
+            vec![
+                "{\"id\":1,\"results\":[{\"begin\":0,\"end\":4,\"text\":\"Sula\",\"nested\":false,\"matches\":[{\"label\":\"uri:sula\",\"type\":\"Full\",\"entry\":\"Sula\",\"corpora\":[],\"provenances\":[],\"score\":0.5,\"distance\":0,\"skipped\":[],\"gaps\":[],\"licensed_by\":null}]}]}",
+                "{\"id\":2,\"line\":4,\"error\":\"max_gap must be at most 3, got 9\"}",
+                "{\"line\":2,\"error\":\"Invalid document in line 2: expected value at line 1 column 1\"}",
+            ]
+        );
+
+        let response = test::call_service(&app, batch("[{\"id\": 1, \"text\": \"Sula\"}")).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn test_admin_reload() {
+        use actix_web::http::{Method, StatusCode};
+        use actix_web::{test, App};
+
+        let reload = |token: Option<&str>| {
+            let state = Arc::new(AppState::new(
+                sula_tree(),
+                Default::default(),
+                token.map(String::from),
+            ));
+            App::new()
+                .app_data(web::Data::new(state))
+                .route("/admin/reload", web::get().to(admin_reload_status))
+                .route("/admin/reload", web::post().to(admin_reload))
+        };
+        let local = || {
+            test::TestRequest::default()
+                .uri("/admin/reload")
+                .peer_addr("127.0.0.1:9714".parse().unwrap())
+        };
+
+        // without a token, reloads are rejected even from localhost, the status is still served
+        let app = test::init_service(reload(None)).await;
+        let response = test::call_service(&app, local().method(Method::POST).to_request()).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = test::call_service(&app, local().to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let app = test::init_service(reload(Some("secret"))).await;
+        let response = test::call_service(&app, local().method(Method::POST).to_request()).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+}
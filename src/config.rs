@@ -0,0 +1,280 @@
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+
+use crate::abbreviation::AbbreviationOptions;
+use crate::fuzzy::FuzzyOptions;
+use crate::skip_gram::{SkipGramOptions, SkipGramPolicy, SkipGramPositions, SkipGramStrategy};
+use crate::taxonomy::TaxonVariantOptions;
+use crate::tree::{HashMapSearchTree, LoadSummary, ScoreWeights, DEFAULT_MAX_GAP};
+use crate::util::{read_lines, CorpusFormat, TokenizerConfig};
+
+const DEFAULT_GENERATE_ABBRV: bool = false;
+const DEFAULT_ABBRV_MAX_INDEX: i32 = 1;
+const DEFAULT_ABBRV_MIN_SUFFIX_LENGTH: i32 = 3;
+const DEFAULT_ABBRV_MAX_SEGMENTS: usize = 1;
+const DEFAULT_ABBRV_PERIOD: bool = false;
+const DEFAULT_GENERATE_SKIP_GRAMS: bool = false;
+const DEFAULT_SKIP_GRAM_MAX_SKIPS: i32 = 2;
+const DEFAULT_SKIP_GRAM_MIN_LENGTH: i32 = 2;
+const DEFAULT_SKIP_GRAM_DROP_COLLISIONS: bool = false;
+const DEFAULT_FUZZY_MAX_DISTANCE: u32 = 0;
+const DEFAULT_FUZZY_MIN_TOKEN_LENGTH: usize = 5;
+
+#[derive(Serialize, Deserialize)]
+pub struct Config {
+    pub filter_path: Option<String>,
+    pub generate_abbrv: Option<bool>,
+    pub abbrv_max_index: Option<i32>,
+    pub abbrv_min_suffix_length: Option<i32>,
+    pub abbrv_max_segments: Option<usize>,
+    pub abbrv_prefix_lengths: Option<Vec<usize>>,
+    pub abbrv_period: Option<bool>,
+    pub generate_skip_grams: Option<bool>,
+    pub skip_gram_min_length: Option<i32>,
+    pub skip_gram_max_skips: Option<i32>,
+    pub skip_gram_strategy: Option<SkipGramStrategy>,
+    pub skip_gram_positions: Option<SkipGramPositions>,
+    pub skip_gram_max_variants: Option<usize>,
+    pub skip_gram_drop_collisions: Option<bool>,
+    pub fuzzy_max_distance: Option<u32>,
+    pub fuzzy_min_token_length: Option<usize>,
+    pub tokenizer: Option<TokenizerConfig>,
+    pub score_weights: Option<ScoreWeights>,
+    /// The largest `max_gap` a request may ask for. Defaults to `DEFAULT_MAX_GAP`.
+    pub max_gap: Option<usize>,
+    pub corpora: HashMap<String, Corpus>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Corpus {
+    pub path: String,
+    pub filter_path: Option<String>,
+    pub generate_abbrv: Option<bool>,
+    pub abbrv_max_index: Option<i32>,
+    pub abbrv_min_suffix_length: Option<i32>,
+    pub abbrv_max_segments: Option<usize>,
+    pub abbrv_prefix_lengths: Option<Vec<usize>>,
+    pub abbrv_period: Option<bool>,
+    pub generate_skip_grams: Option<bool>,
+    pub skip_gram_min_length: Option<i32>,
+    pub skip_gram_max_skips: Option<i32>,
+    pub skip_gram_strategy: Option<SkipGramStrategy>,
+    pub skip_gram_positions: Option<SkipGramPositions>,
+    pub skip_gram_max_variants: Option<usize>,
+    pub skip_gram_drop_collisions: Option<bool>,
+    pub fuzzy_max_distance: Option<u32>,
+    pub fuzzy_min_token_length: Option<usize>,
+    pub format: Option<CorpusFormat>,
+    /// If given, canonical variants of the scientific names of this corpus are generated.
+    pub taxon_variants: Option<TaxonVariantOptions>,
+}
+
+/// A parsed configuration together with the raw TOML it was parsed from.
+pub struct LoadedConfig {
+    pub config: Config,
+    pub raw: String,
+}
+
+impl LoadedConfig {
+    pub fn from_path(config_path: &str) -> anyhow::Result<Self> {
+        let raw: String =
+            std::fs::read_to_string(config_path).context("Failed to load configuration.")?;
+        let config: Config = toml::from_str(&raw).context("Failed to parse configuration TOML")?;
+        Ok(LoadedConfig { config, raw })
+    }
+
+    /// A stable hash of the raw configuration, used to tie index snapshots to the
+    /// configuration that produced them.
+    #[must_use]
+    pub fn hash(&self) -> u64 {
+        config_hash(&self.raw)
+    }
+}
+
+/// 64-bit FNV-1a hash. Unlike `std::hash::DefaultHasher`, its output is guaranteed to be stable
+/// across Rust releases, so it can be persisted.
+#[must_use]
+pub fn config_hash(raw: &str) -> u64 {
+    raw.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+pub fn build_tree(config: &Config) -> anyhow::Result<HashMapSearchTree> {
+    let tokenizer = config
+        .tokenizer
+        .clone()
+        .unwrap_or_default()
+        .build()
+        .context("Failed to build tokenizer")?;
+    let mut tree = HashMapSearchTree::with_tokenizer(tokenizer);
+    tree.set_score_weights(config.score_weights.unwrap_or_default());
+    tree.set_max_gap(config.max_gap.unwrap_or(DEFAULT_MAX_GAP));
+    let default_filter_list = load_filter_list(config.filter_path.clone());
+    let mut summaries: BTreeMap<&String, LoadSummary> = BTreeMap::new();
+
+    for (name, corpus) in &config.corpora {
+        let root_path: &String = &corpus.path;
+        let generate_abbrv = corpus
+            .generate_abbrv
+            .unwrap_or_else(|| config.generate_abbrv.unwrap_or(DEFAULT_GENERATE_ABBRV));
+        let abbrv_max_index = corpus
+            .abbrv_max_index
+            .unwrap_or_else(|| config.abbrv_max_index.unwrap_or(DEFAULT_ABBRV_MAX_INDEX));
+        let abbrv_min_suffix_length = corpus.abbrv_min_suffix_length.unwrap_or_else(|| {
+            config
+                .abbrv_min_suffix_length
+                .unwrap_or(DEFAULT_ABBRV_MIN_SUFFIX_LENGTH)
+        });
+        let abbreviation_options = AbbreviationOptions {
+            max_segments: corpus.abbrv_max_segments.unwrap_or_else(|| {
+                config
+                    .abbrv_max_segments
+                    .unwrap_or(DEFAULT_ABBRV_MAX_SEGMENTS)
+            }),
+            prefix_lengths: corpus
+                .abbrv_prefix_lengths
+                .clone()
+                .or_else(|| config.abbrv_prefix_lengths.clone())
+                .unwrap_or_else(|| vec![1]),
+            period: corpus
+                .abbrv_period
+                .unwrap_or_else(|| config.abbrv_period.unwrap_or(DEFAULT_ABBRV_PERIOD)),
+        };
+        if generate_abbrv
+            && abbreviation_options.period
+            && !tree.tokenize(".").0.contains(&String::from("."))
+        {
+            bail!(
+                "Corpus '{name}' sets abbrv_period, but the tokenizer removes periods. \
+                Use a pre-tokenizer that keeps punctuation, such as \"Bert\"."
+            );
+        }
+        let generate_skip_grams = corpus.generate_skip_grams.unwrap_or_else(|| {
+            config
+                .generate_skip_grams
+                .unwrap_or(DEFAULT_GENERATE_SKIP_GRAMS)
+        });
+        let skip_gram_min_length = corpus.skip_gram_min_length.unwrap_or_else(|| {
+            config
+                .skip_gram_min_length
+                .unwrap_or(DEFAULT_SKIP_GRAM_MIN_LENGTH)
+        });
+        let skip_gram_max_skips = corpus.skip_gram_max_skips.unwrap_or_else(|| {
+            config
+                .skip_gram_max_skips
+                .unwrap_or(DEFAULT_SKIP_GRAM_MAX_SKIPS)
+        });
+        let skip_gram_strategy = corpus
+            .skip_gram_strategy
+            .unwrap_or_else(|| config.skip_gram_strategy.unwrap_or_default());
+        let skip_gram_policy = SkipGramPolicy {
+            positions: corpus
+                .skip_gram_positions
+                .unwrap_or_else(|| config.skip_gram_positions.unwrap_or_default()),
+            max_variants: corpus
+                .skip_gram_max_variants
+                .or(config.skip_gram_max_variants),
+            drop_collisions: corpus.skip_gram_drop_collisions.unwrap_or_else(|| {
+                config
+                    .skip_gram_drop_collisions
+                    .unwrap_or(DEFAULT_SKIP_GRAM_DROP_COLLISIONS)
+            }),
+        };
+        let lazy_skip_grams = generate_skip_grams && skip_gram_strategy == SkipGramStrategy::Query;
+        let generate_skip_grams = generate_skip_grams && !lazy_skip_grams;
+        let fuzzy_max_distance = corpus.fuzzy_max_distance.unwrap_or_else(|| {
+            config
+                .fuzzy_max_distance
+                .unwrap_or(DEFAULT_FUZZY_MAX_DISTANCE)
+        });
+        let fuzzy_min_token_length = corpus.fuzzy_min_token_length.unwrap_or_else(|| {
+            config
+                .fuzzy_min_token_length
+                .unwrap_or(DEFAULT_FUZZY_MIN_TOKEN_LENGTH)
+        });
+        let format = &corpus.format;
+        tree.set_skip_gram_policy(name, skip_gram_policy);
+        tree.set_abbreviation_options(name, abbreviation_options);
+        if let Some(options) = corpus.taxon_variants {
+            tree.enable_taxon_variants(name, options);
+        }
+        let summary = if let Some(filter_path) = &corpus.filter_path {
+            let lines: Vec<String> = read_lines(filter_path);
+            let filter_list = if lines.is_empty() {
+                None
+            } else {
+                Option::from(lines)
+            };
+            tree.load_file(
+                name,
+                root_path,
+                generate_skip_grams,
+                skip_gram_min_length,
+                skip_gram_max_skips,
+                &filter_list,
+                generate_abbrv,
+                abbrv_max_index,
+                abbrv_min_suffix_length,
+                format,
+            )?
+        } else {
+            tree.load_file(
+                name,
+                root_path,
+                generate_skip_grams,
+                skip_gram_min_length,
+                skip_gram_max_skips,
+                &default_filter_list,
+                generate_abbrv,
+                abbrv_max_index,
+                abbrv_min_suffix_length,
+                format,
+            )?
+        };
+        if generate_skip_grams || lazy_skip_grams {
+            log::info!("Skip-gram policy of corpus '{name}': {skip_gram_policy:?}");
+        }
+        summaries.insert(name, summary);
+        if lazy_skip_grams {
+            tree.enable_lazy_skip_grams(
+                name,
+                SkipGramOptions {
+                    min_length: skip_gram_min_length,
+                    max_skips: skip_gram_max_skips,
+                },
+            );
+        }
+        if fuzzy_max_distance > 0 {
+            tree.enable_fuzzy(
+                name,
+                FuzzyOptions {
+                    max_distance: fuzzy_max_distance,
+                    min_token_length: fuzzy_min_token_length,
+                },
+            );
+        }
+    }
+    for (corpus, dropped) in tree.drop_colliding_skip_grams() {
+        if let Some(summary) = summaries.get_mut(&corpus) {
+            summary.skip_grams = summary.skip_grams.saturating_sub(dropped);
+            summary.colliding_skip_grams = dropped;
+        }
+    }
+    for (name, summary) in &summaries {
+        log::info!("Loaded corpus '{name}': {summary}");
+    }
+    log::info!("Finished loading gazetteer with {} entries", tree.len());
+    Ok(tree)
+}
+
+fn load_filter_list(filter_path: Option<String>) -> Option<Vec<String>> {
+    let lines = filter_path.map_or_else(Vec::new, |p| read_lines(&p));
+    if lines.is_empty() {
+        None
+    } else {
+        Option::from(lines)
+    }
+}
@@ -0,0 +1,207 @@
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+
+/// Per-corpus settings for character-level fuzzy matching.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FuzzyOptions {
+    /// The maximum edit distance between a document token and an entry token.
+    pub max_distance: u32,
+    /// Tokens shorter than this (in characters) are only ever matched exactly.
+    pub min_token_length: usize,
+}
+
+/// The state of a fuzzy path through the search tree.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct FuzzyPath {
+    /// The sum of the edit distances of all tokens on the path.
+    pub(crate) distance: u32,
+    /// The largest edit distance of a single token on the path.
+    pub(crate) max_token_distance: u32,
+    /// The length of the shortest token that was not matched exactly.
+    pub(crate) min_token_length: usize,
+}
+
+impl Default for FuzzyPath {
+    fn default() -> Self {
+        FuzzyPath {
+            distance: 0,
+            max_token_distance: 0,
+            min_token_length: usize::MAX,
+        }
+    }
+}
+
+impl FuzzyPath {
+    pub(crate) fn step(&self, distance: u32, token_length: usize) -> Self {
+        if distance == 0 {
+            return *self;
+        }
+        FuzzyPath {
+            distance: self.distance + distance,
+            max_token_distance: self.max_token_distance.max(distance),
+            min_token_length: self.min_token_length.min(token_length),
+        }
+    }
+}
+
+/// A symmetric delete index (as in SymSpell) over the tokens of all entries from fuzzy-enabled
+/// corpora. Both the indexed tokens and the query tokens are reduced to all of their variants with
+/// up to `max_distance` deleted characters; tokens sharing a variant are candidates, which are then
+/// verified with the actual edit distance.
+#[derive(Debug, Default)]
+pub(crate) struct FuzzyIndex {
+    pub(crate) corpora: HashMap<String, FuzzyOptions>,
+    max_distance: u32,
+    min_token_length: usize,
+    /// Maps each deletion variant of an indexed token to the ids of these tokens.
+    deletes: HashMap<String, Vec<u32>>,
+    /// The indexed tokens by id, with the distance they were indexed for.
+    tokens: HashMap<u32, (String, u32)>,
+}
+
+impl FuzzyIndex {
+    pub(crate) fn is_enabled(&self) -> bool {
+        !self.corpora.is_empty()
+    }
+
+    pub(crate) fn add_corpus(&mut self, corpus: &str, options: FuzzyOptions) {
+        if self.corpora.is_empty() {
+            self.min_token_length = options.min_token_length;
+        }
+        self.max_distance = self.max_distance.max(options.max_distance);
+        self.min_token_length = self.min_token_length.min(options.min_token_length);
+        self.corpora.insert(String::from(corpus), options);
+    }
+
+    pub(crate) fn add_token(&mut self, id: u32, token: &str, options: &FuzzyOptions) {
+        if token.chars().count() < options.min_token_length {
+            return;
+        }
+        let indexed_distance = self.tokens.get(&id).map_or(0, |(_, distance)| *distance);
+        if self.tokens.contains_key(&id) && indexed_distance >= options.max_distance {
+            return;
+        }
+        for variant in deletes(token, options.max_distance) {
+            let ids = self.deletes.entry(variant).or_default();
+            if !ids.contains(&id) {
+                ids.push(id);
+            }
+        }
+        self.tokens
+            .insert(id, (String::from(token), options.max_distance));
+    }
+
+    /// Returns the ids of all indexed tokens within the maximum edit distance of the given token,
+    /// excluding the token itself, as `(id, distance, shorter token length)`.
+    pub(crate) fn candidates(&self, token: &str) -> Vec<(u32, u32, usize)> {
+        let length = token.chars().count();
+        if length < self.min_token_length {
+            return Vec::new();
+        }
+        let ids: HashSet<u32> = deletes(token, self.max_distance)
+            .iter()
+            .filter_map(|variant| self.deletes.get(variant))
+            .flatten()
+            .copied()
+            .collect();
+        let mut candidates: Vec<(u32, u32, usize)> = ids
+            .into_iter()
+            .filter_map(|id| {
+                let (candidate, indexed_distance) = &self.tokens[&id];
+                let distance = levenshtein(token, candidate);
+                if distance > 0 && distance <= *indexed_distance {
+                    Some((id, distance, length.min(candidate.chars().count())))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        candidates.sort_unstable();
+        candidates
+    }
+
+    /// Whether a fuzzy path is within the limits of the given corpus.
+    pub(crate) fn accepts(&self, corpus: Option<&str>, path: &FuzzyPath) -> bool {
+        corpus
+            .and_then(|corpus| self.corpora.get(corpus))
+            .is_some_and(|options| {
+                path.max_token_distance <= options.max_distance
+                    && path.min_token_length >= options.min_token_length
+            })
+    }
+}
+
+/// All variants of the token with up to `max_distance` characters deleted, including the token
+/// itself.
+fn deletes(token: &str, max_distance: u32) -> HashSet<String> {
+    let mut variants: HashSet<String> = HashSet::from([String::from(token)]);
+    let mut current: Vec<Vec<char>> = vec![token.chars().collect()];
+    for _ in 0..max_distance {
+        let mut next = Vec::new();
+        for chars in &current {
+            for i in 0..chars.len() {
+                let mut variant = chars.clone();
+                variant.remove(i);
+                if variants.insert(variant.iter().collect()) {
+                    next.push(variant);
+                }
+            }
+        }
+        current = next;
+    }
+    variants
+}
+
+/// The Levenshtein distance between two strings, counted in characters.
+#[must_use]
+pub fn levenshtein(a: &str, b: &str) -> u32 {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<u32> = (0..=b.len() as u32).collect();
+    let mut current = vec![0; b.len() + 1];
+    for (i, ca) in a.chars().enumerate() {
+        current[0] = i as u32 + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + u32::from(ca != *cb);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_levenshtein() {
+        assert_eq!(levenshtein("puffinus", "puffinus"), 0);
+        assert_eq!(levenshtein("puffinis", "puffinus"), 1);
+        assert_eq!(levenshtein("pufinus", "puffinus"), 1);
+        assert_eq!(levenshtein("", "sula"), 4);
+        assert_eq!(levenshtein("größe", "grösse"), 2);
+    }
+
+    #[test]
+    fn test_candidates() {
+        let options = FuzzyOptions {
+            max_distance: 1,
+            min_token_length: 4,
+        };
+        let mut index = FuzzyIndex::default();
+        index.add_corpus("corpus", options);
+        index.add_token(0, "puffinus", &options);
+        index.add_token(1, "sula", &options);
+        index.add_token(2, "p", &options);
+
+        assert_eq!(index.candidates("puffinis"), vec![(0, 1, 8)]);
+        assert_eq!(index.candidates("pufinus"), vec![(0, 1, 7)]);
+        assert_eq!(index.candidates("puffinus"), vec![]);
+        assert_eq!(index.candidates("pofinis"), vec![]);
+        assert_eq!(index.candidates("sulu"), vec![(1, 1, 4)]);
+        // short tokens are never fuzzy matched
+        assert_eq!(index.candidates("q"), vec![]);
+        assert_eq!(index.candidates("sul"), vec![]);
+    }
+}
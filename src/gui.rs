@@ -1,4 +1,7 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
 
 use lazy_static::lazy_static;
 use serde::Deserialize;
@@ -6,7 +9,11 @@ use serde::Deserialize;
 use actix_web::{web, HttpResponse};
 use tera::{Context, Tera};
 
-use crate::{tree::ResultSelection, AppState};
+use crate::{
+    tree::{CorpusFilter, ResultSelection, SearchOptions, SearchResult},
+    util::OffsetUnit,
+    AppState,
+};
 
 lazy_static! {
     pub static ref TEMPLATES: Tera = {
@@ -22,12 +29,14 @@ lazy_static! {
     };
 }
 
-pub async fn index() -> HttpResponse {
+pub async fn index(state: web::Data<Arc<AppState>>) -> HttpResponse {
     let mut context = Context::new();
     let errors: Vec<String> = Vec::new();
     let values: HashMap<String, String> = HashMap::new();
     context.insert("errors", &errors);
     context.insert("values", &values);
+    context.insert("corpora", state.tree().corpora());
+    context.insert("max_gap", &state.tree().max_gap());
     let body = TEMPLATES
         .render("index.html.tera", &context)
         .expect("Failed to render template!");
@@ -39,16 +48,48 @@ pub struct FormData {
     text: String,
     max_len: Option<usize>,
     result_selection: Option<ResultSelection>,
+    /// The checked corpora. Unchecked corpora are excluded from the search.
+    #[serde(default)]
+    corpora: HashSet<String>,
+    #[serde(default)]
+    license_abbreviations: bool,
+    min_score: Option<f64>,
+    max_gap: Option<usize>,
 }
 
-pub async fn process_form(
-    form: web::Form<FormData>,
-    state: web::Data<Arc<AppState>>,
-) -> HttpResponse {
-    let results: &Vec<(String, Vec<crate::tree::Match>, usize, usize)> =
-        &state
-            .tree
-            .search(&form.text, form.max_len, form.result_selection.as_ref());
+/// Parses the form body with `serde_html_form`, as `web::Form` does not support repeated keys
+/// like the corpus checkboxes.
+pub async fn process_form(body: web::Bytes, state: web::Data<Arc<AppState>>) -> HttpResponse {
+    let form: FormData = match serde_html_form::from_bytes(&body) {
+        Ok(form) => form,
+        Err(e) => return HttpResponse::BadRequest().body(format!("Invalid form data: {e}")),
+    };
+    let tree = state.tree();
+    if let Some(max_gap) = form.max_gap.filter(|max_gap| *max_gap > tree.max_gap()) {
+        return HttpResponse::BadRequest().body(format!(
+            "max_gap must be at most {}, got {max_gap}",
+            tree.max_gap()
+        ));
+    }
+    let corpus_filter = CorpusFilter {
+        include: Some(form.corpora),
+        exclude: None,
+    };
+    let corpus_filter = if tree.corpora().is_empty() {
+        None
+    } else {
+        Some(corpus_filter)
+    };
+    let options = SearchOptions {
+        max_len: form.max_len,
+        result_selection: form.result_selection,
+        corpus_filter,
+        license_abbreviations: form.license_abbreviations,
+        min_score: form.min_score,
+        max_gap: form.max_gap,
+        offset_unit: OffsetUnit::Char,
+    };
+    let results: &Vec<SearchResult> = &tree.search(&form.text, &options);
 
     let mut context = Context::new();
     context.insert("results", results);
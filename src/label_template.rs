@@ -0,0 +1,146 @@
+use anyhow::{anyhow, bail};
+use csv::StringRecord;
+
+use crate::util::Column;
+
+/// A label built from any number of columns, i.e. `https://www.gbif.org/species/{taxonKey}`.
+/// Placeholders name a column by its header or by its index and may list fallback columns that
+/// are used if the column is empty, i.e. `{taxonKey|acceptedKey}`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LabelTemplate {
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    /// The column and its fallbacks, in order.
+    Placeholder(Vec<Column>),
+}
+
+impl TryFrom<&str> for LabelTemplate {
+    type Error = anyhow::Error;
+
+    fn try_from(template: &str) -> Result<Self, Self::Error> {
+        let mut segments = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            push_literal(&mut segments, &rest[..start], template)?;
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| anyhow!("Unclosed placeholder in label template '{template}'"))?;
+            let placeholder = &rest[start + 1..start + end];
+            let columns: Vec<Column> = placeholder
+                .split('|')
+                .map(str::trim)
+                .map(|column| match column.parse::<usize>() {
+                    Ok(idx) => Column::Index(idx),
+                    Err(_) => Column::Header(String::from(column)),
+                })
+                .collect();
+            if columns
+                .iter()
+                .any(|column| matches!(column, Column::Header(header) if header.is_empty()))
+            {
+                bail!("Empty placeholder in label template '{template}'");
+            }
+            segments.push(Segment::Placeholder(columns));
+            rest = &rest[start + end + 1..];
+        }
+        push_literal(&mut segments, rest, template)?;
+        Ok(LabelTemplate { segments })
+    }
+}
+
+/// Adds a literal segment of the template, unless it is empty.
+fn push_literal(segments: &mut Vec<Segment>, literal: &str, template: &str) -> anyhow::Result<()> {
+    if literal.contains('}') {
+        bail!("Unopened placeholder in label template '{template}'");
+    }
+    if !literal.is_empty() {
+        segments.push(Segment::Literal(String::from(literal)));
+    }
+    Ok(())
+}
+
+/// A label template with its columns resolved for a specific input, see `LabelTemplate`.
+pub(crate) struct CompiledLabelTemplate {
+    segments: Vec<CompiledSegment>,
+}
+
+enum CompiledSegment {
+    Literal(String),
+    Placeholder(Vec<usize>),
+}
+
+impl CompiledLabelTemplate {
+    pub(crate) fn new(
+        template: &LabelTemplate,
+        headers: Option<&StringRecord>,
+    ) -> anyhow::Result<Self> {
+        let segments = template
+            .segments
+            .iter()
+            .map(|segment| {
+                Ok(match segment {
+                    Segment::Literal(literal) => CompiledSegment::Literal(literal.clone()),
+                    Segment::Placeholder(columns) => CompiledSegment::Placeholder(
+                        columns
+                            .iter()
+                            .map(|column| column.resolve(headers).map(|(idx, _)| idx))
+                            .collect::<anyhow::Result<_>>()?,
+                    ),
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(CompiledLabelTemplate { segments })
+    }
+
+    /// Renders the label of the given row. Returns None if all columns of a placeholder are
+    /// missing or empty.
+    pub(crate) fn render(&self, row: &StringRecord) -> Option<String> {
+        let mut label = String::new();
+        for segment in &self.segments {
+            match segment {
+                CompiledSegment::Literal(literal) => label.push_str(literal),
+                CompiledSegment::Placeholder(columns) => label.push_str(
+                    columns
+                        .iter()
+                        .filter_map(|idx| row.get(*idx))
+                        .find(|value| !value.is_empty())?,
+                ),
+            }
+        }
+        Some(label)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_label_template() {
+        let headers = StringRecord::from(vec!["name", "taxonKey", "acceptedKey"]);
+        let template =
+            LabelTemplate::try_from("https://www.gbif.org/species/{taxonKey|acceptedKey}#{0}")
+                .unwrap();
+        let template = CompiledLabelTemplate::new(&template, Some(&headers)).unwrap();
+        assert_eq!(
+            template.render(&StringRecord::from(vec!["Sula", "2480966", "2480967"])),
+            Some(String::from("https://www.gbif.org/species/2480966#Sula"))
+        );
+        assert_eq!(
+            template.render(&StringRecord::from(vec!["Sula", "", "2480967"])),
+            Some(String::from("https://www.gbif.org/species/2480967#Sula"))
+        );
+        assert_eq!(template.render(&StringRecord::from(vec!["Sula", ""])), None);
+
+        assert!(LabelTemplate::try_from("uri:{taxonKey").is_err());
+        assert!(LabelTemplate::try_from("uri:taxonKey}").is_err());
+        assert!(LabelTemplate::try_from("uri:}{taxonKey}").is_err());
+        assert!(LabelTemplate::try_from("uri:{}").is_err());
+        let unknown = LabelTemplate::try_from("uri:{family}").unwrap();
+        assert!(CompiledLabelTemplate::new(&unknown, Some(&headers)).is_err());
+    }
+}
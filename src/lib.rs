@@ -1,12 +1,250 @@
+pub mod abbreviation;
 pub mod api;
+pub mod config;
+pub mod fuzzy;
+pub mod label_template;
+pub mod row_filter;
+pub mod skip_gram;
+pub mod snapshot;
+pub mod tag;
+pub mod taxonomy;
 pub mod tree;
 pub mod util;
 
+use std::net::IpAddr;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::{Arc, Mutex, RwLock};
+
+use serde::Serialize;
+
+use crate::config::{build_tree, LoadedConfig};
 use crate::tree::HashMapSearchTree;
 
+/// Where the tree is loaded from, both on startup and on every reload.
+#[derive(Debug, Clone, Default)]
+pub struct TreeSource {
+    pub config_path: String,
+    /// If given, the tree is loaded from this index snapshot instead of being built from the
+    /// corpora in the configuration.
+    pub index: Option<String>,
+    /// If given, every built tree is saved as an index snapshot to this path.
+    pub save_index: Option<String>,
+}
+
+impl TreeSource {
+    pub fn load(&self) -> anyhow::Result<HashMapSearchTree> {
+        let config = LoadedConfig::from_path(&self.config_path)?;
+        if let Some(index) = &self.index {
+            log::info!("Loading index snapshot from {index}");
+            let tree = snapshot::load(index, config.hash())?;
+            log::info!("Finished loading gazetteer with {} entries", tree.len());
+            return Ok(tree);
+        }
+
+        let tree = build_tree(&config.config)?;
+        if let Some(save_index) = &self.save_index {
+            log::info!("Saving index snapshot to {save_index}");
+            snapshot::save(&tree, save_index, config.hash(), &config.raw)?;
+        }
+        Ok(tree)
+    }
+}
+
+/// The state of the last tree rebuild triggered via `AppState::reload`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum ReloadStatus {
+    Idle,
+    Running,
+    Succeeded { entries: usize },
+    Failed { error: String },
+}
+
 pub struct AppState {
-    pub tree: HashMapSearchTree,
+    tree: RwLock<Arc<HashMapSearchTree>>,
+    source: TreeSource,
+    /// The token that admin requests must present. Without a token, reloads can only be triggered
+    /// by `SIGHUP` and the reload status is only served to the loopback interface.
+    admin_token: Option<String>,
+    reload_status: Mutex<ReloadStatus>,
+}
+
+impl AppState {
+    #[must_use]
+    pub fn new(tree: HashMapSearchTree, source: TreeSource, admin_token: Option<String>) -> Self {
+        AppState {
+            tree: RwLock::new(Arc::new(tree)),
+            source,
+            admin_token,
+            reload_status: Mutex::new(ReloadStatus::Idle),
+        }
+    }
+
+    /// Whether admin requests that change the state, i.e. reloads, are accepted at all. Without a
+    /// token, a local reverse proxy would make every request look like it came from loopback.
+    #[must_use]
+    pub fn has_admin_token(&self) -> bool {
+        self.admin_token.is_some()
+    }
+
+    /// Whether an admin request with the given bearer token from a peer with the given address
+    /// is authorized.
+    #[must_use]
+    pub fn authorizes_admin(&self, token: Option<&str>, peer: Option<IpAddr>) -> bool {
+        match &self.admin_token {
+            Some(admin_token) => token.is_some_and(|token| {
+                // compares in constant time to not leak the token through timing
+                token.len() == admin_token.len()
+                    && token
+                        .bytes()
+                        .zip(admin_token.bytes())
+                        .fold(0, |diff, (a, b)| diff | (a ^ b))
+                        == 0
+            }),
+            None => peer.is_some_and(|peer| peer.is_loopback()),
+        }
+    }
+
+    /// The currently served tree. Callers keep using the returned tree even if it is swapped
+    /// out by a reload in the meantime.
+    pub fn tree(&self) -> Arc<HashMapSearchTree> {
+        self.tree.read().unwrap().clone()
+    }
+
+    pub fn reload_status(&self) -> ReloadStatus {
+        self.reload_status.lock().unwrap().clone()
+    }
+
+    /// Reloads the tree from its `TreeSource` in a background thread and swaps it in once it is
+    /// finished. If the rebuild fails, the old tree keeps serving and the error is
+    /// reported in the reload status. Returns false if a rebuild is already running.
+    pub fn reload(self: &Arc<Self>) -> bool {
+        {
+            let mut status = self.reload_status.lock().unwrap();
+            if let ReloadStatus::Running = *status {
+                return false;
+            }
+            *status = ReloadStatus::Running;
+        }
+
+        let state = self.clone();
+        std::thread::spawn(move || {
+            log::info!("Rebuilding gazetteer from {}", state.source.config_path);
+            let result = catch_unwind(AssertUnwindSafe(|| state.source.load()))
+                .unwrap_or_else(|_| Err(anyhow::anyhow!("Rebuilding the tree panicked")));
+
+            let status = match result {
+                Ok(tree) => {
+                    let entries = tree.len();
+                    *state.tree.write().unwrap() = Arc::new(tree);
+                    log::info!("Swapped in rebuilt gazetteer with {entries} entries");
+                    ReloadStatus::Succeeded { entries }
+                }
+                Err(error) => {
+                    log::error!("Failed to rebuild gazetteer: {error:#}");
+                    ReloadStatus::Failed {
+                        error: format!("{error:#}"),
+                    }
+                }
+            };
+            *state.reload_status.lock().unwrap() = status;
+        });
+        true
+    }
 }
 
 #[cfg(feature = "gui")]
 pub mod gui;
+
+/// A tree with the single entry "Sula" labeled "uri:sula", for tests.
+#[cfg(test)]
+pub(crate) fn sula_tree() -> HashMapSearchTree {
+    let mut tree = HashMapSearchTree::default();
+    tree.load(
+        vec![("Sula".to_string(), "uri:sula".to_string())],
+        false,
+        0,
+        0,
+        false,
+        0,
+        3,
+    );
+    tree
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::util::TempDir;
+
+    fn wait_for_reload(state: &AppState) {
+        while let ReloadStatus::Running = state.reload_status() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_failed_reload_keeps_tree() {
+        let tree = sula_tree();
+        let source = TreeSource {
+            config_path: String::from("does/not/exist.toml"),
+            ..Default::default()
+        };
+        let state = Arc::new(AppState::new(tree, source, None));
+
+        assert!(state.reload());
+        wait_for_reload(&state);
+        assert!(matches!(state.reload_status(), ReloadStatus::Failed { .. }));
+        assert_eq!(state.tree().len(), 1);
+    }
+
+    #[test]
+    fn test_reload_from_index() {
+        let dir = TempDir::new("reload");
+        let config_path = dir.path().join("config.toml");
+        let index_path = dir.path().join("index.bin");
+        // the configuration has no corpora, so only the snapshot yields any entries
+        let raw = "[corpora]\n";
+        std::fs::write(&config_path, raw).unwrap();
+        snapshot::save(
+            &sula_tree(),
+            index_path.to_str().unwrap(),
+            config::config_hash(raw),
+            raw,
+        )
+        .unwrap();
+
+        let source = TreeSource {
+            config_path: String::from(config_path.to_str().unwrap()),
+            index: Some(String::from(index_path.to_str().unwrap())),
+            save_index: None,
+        };
+        let state = Arc::new(AppState::new(HashMapSearchTree::default(), source, None));
+        assert!(state.reload());
+        wait_for_reload(&state);
+        assert!(matches!(
+            state.reload_status(),
+            ReloadStatus::Succeeded { entries: 1 }
+        ));
+        assert_eq!(state.tree().len(), 1);
+    }
+
+    #[test]
+    fn test_authorizes_admin() {
+        let local = Some(IpAddr::from([127, 0, 0, 1]));
+        let remote = Some(IpAddr::from([192, 168, 0, 2]));
+
+        let state = AppState::new(HashMapSearchTree::default(), TreeSource::default(), None);
+        assert!(state.authorizes_admin(None, local));
+        assert!(!state.authorizes_admin(None, remote));
+        assert!(!state.authorizes_admin(Some("secret"), remote));
+
+        let token = Some(String::from("secret"));
+        let state = AppState::new(HashMapSearchTree::default(), TreeSource::default(), token);
+        assert!(state.authorizes_admin(Some("secret"), remote));
+        assert!(!state.authorizes_admin(Some("secrets"), remote));
+        assert!(!state.authorizes_admin(None, local));
+    }
+}
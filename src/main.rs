@@ -1,149 +1,33 @@
-use std::collections::HashMap;
+use std::io;
 use std::sync::Arc;
 
-use serde::{Deserialize, Serialize};
-
 use anyhow::Context;
-use clap::{arg, Parser};
+use clap::{Parser, Subcommand};
 
 use actix_files as fs;
 use actix_web::{web, App, HttpServer};
 
-use gazetteer::api;
-use gazetteer::tree::HashMapSearchTree;
-use gazetteer::util::{read_lines, CorpusFormat};
-use gazetteer::AppState;
+use gazetteer::api::{self, ProcessOptions};
+use gazetteer::tag::{self, TagFormat};
+use gazetteer::{AppState, TreeSource};
+
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
 
 #[cfg(feature = "gui")]
 use gazetteer::gui;
 
-const DEFAULT_GENERATE_ABBRV: bool = false;
-const DEFAULT_ABBRV_MAX_INDEX: i32 = 1;
-const DEFAULT_ABBRV_MIN_SUFFIX_LENGTH: i32 = 3;
-const DEFAULT_GENERATE_SKIP_GRAMS: bool = false;
-const DEFAULT_SKIP_GRAM_MAX_SKIPS: i32 = 2;
-const DEFAULT_SKIP_GRAM_MIN_LENGTH: i32 = 2;
-
 #[cfg(debug_assertions)]
 const LOG_LEVEL: &str = "debug";
 #[cfg(not(debug_assertions))]
 const LOG_LEVEL: &str = "info";
 
-#[derive(Serialize, Deserialize)]
-struct Config {
-    filter_path: Option<String>,
-    generate_abbrv: Option<bool>,
-    abbrv_max_index: Option<i32>,
-    abbrv_min_suffix_length: Option<i32>,
-    generate_skip_grams: Option<bool>,
-    skip_gram_min_length: Option<i32>,
-    skip_gram_max_skips: Option<i32>,
-    corpora: HashMap<String, Corpus>,
-}
-
-#[derive(Serialize, Deserialize)]
-struct Corpus {
-    path: String,
-    filter_path: Option<String>,
-    generate_abbrv: Option<bool>,
-    abbrv_max_index: Option<i32>,
-    abbrv_min_suffix_length: Option<i32>,
-    generate_skip_grams: Option<bool>,
-    skip_gram_min_length: Option<i32>,
-    skip_gram_max_skips: Option<i32>,
-    format: Option<CorpusFormat>,
-}
-
-fn parse_args_and_build_tree(config_path: &str) -> anyhow::Result<HashMapSearchTree> {
-    let config: String =
-        std::fs::read_to_string(config_path).context("Failed to load configuration.")?;
-
-    let config: Config = toml::from_str(&config).context("Failed to parse configuration TOML")?;
-
-    let mut tree = HashMapSearchTree::default();
-    let default_filter_list = load_filter_list(config.filter_path);
-
-    for corpus in config.corpora.values() {
-        let root_path: &String = &corpus.path;
-        let generate_abbrv = corpus
-            .generate_abbrv
-            .unwrap_or_else(|| config.generate_abbrv.unwrap_or(DEFAULT_GENERATE_ABBRV));
-        let abbrv_max_index = corpus
-            .abbrv_max_index
-            .unwrap_or_else(|| config.abbrv_max_index.unwrap_or(DEFAULT_ABBRV_MAX_INDEX));
-        let abbrv_min_suffix_length = corpus.abbrv_min_suffix_length.unwrap_or_else(|| {
-            config
-                .abbrv_min_suffix_length
-                .unwrap_or(DEFAULT_ABBRV_MIN_SUFFIX_LENGTH)
-        });
-        let generate_skip_grams = corpus.generate_skip_grams.unwrap_or_else(|| {
-            config
-                .generate_skip_grams
-                .unwrap_or(DEFAULT_GENERATE_SKIP_GRAMS)
-        });
-        let skip_gram_min_length = corpus.skip_gram_min_length.unwrap_or_else(|| {
-            config
-                .skip_gram_min_length
-                .unwrap_or(DEFAULT_SKIP_GRAM_MIN_LENGTH)
-        });
-        let skip_gram_max_skips = corpus.skip_gram_max_skips.unwrap_or_else(|| {
-            config
-                .skip_gram_max_skips
-                .unwrap_or(DEFAULT_SKIP_GRAM_MAX_SKIPS)
-        });
-        let format = &corpus.format;
-        if let Some(filter_path) = &corpus.filter_path {
-            let lines: Vec<String> = read_lines(filter_path);
-            let filter_list = if lines.is_empty() {
-                None
-            } else {
-                Option::from(lines)
-            };
-            tree.load_file(
-                root_path,
-                generate_skip_grams,
-                skip_gram_min_length,
-                skip_gram_max_skips,
-                &filter_list,
-                generate_abbrv,
-                abbrv_max_index,
-                abbrv_min_suffix_length,
-                format,
-            );
-        } else {
-            tree.load_file(
-                root_path,
-                generate_skip_grams,
-                skip_gram_min_length,
-                skip_gram_max_skips,
-                &default_filter_list,
-                generate_abbrv,
-                abbrv_max_index,
-                abbrv_min_suffix_length,
-                format,
-            );
-        }
-    }
-    println!(
-        "Finished loading gazetteer with {} entries",
-        tree.search_map.len()
-    );
-    Ok(tree)
-}
-
-fn load_filter_list(filter_path: Option<String>) -> Option<Vec<String>> {
-    let lines = filter_path.map_or_else(Vec::new, |p| read_lines(&p));
-    if lines.is_empty() {
-        None
-    } else {
-        Option::from(lines)
-    }
-}
-
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    #[arg(short, long, default_value_t = String::from("config.toml"))]
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[arg(short, long, global = true, default_value_t = String::from("config.toml"))]
     config: String,
     #[arg(short, long, default_value_t = String::from("0.0.0.0"))]
     address: String,
@@ -153,24 +37,160 @@ struct Args {
     workers: usize,
     #[arg(long, default_value_t = 16_777_216, help = "The request size limit")]
     limit: usize,
+    #[arg(
+        long,
+        global = true,
+        conflicts_with = "save_index",
+        help = "Load a prebuilt index snapshot instead of the corpora in the configuration"
+    )]
+    index: Option<String>,
+    #[arg(
+        long,
+        global = true,
+        help = "Save the built index as a snapshot to the given path"
+    )]
+    save_index: Option<String>,
+    #[arg(
+        long,
+        env = "GAZETTEER_ADMIN_TOKEN",
+        help = "The bearer token required by /admin/reload, without it reloads are only triggered by SIGHUP"
+    )]
+    admin_token: Option<String>,
 }
 
-#[actix_web::main]
-async fn main() -> anyhow::Result<()> {
+impl Args {
+    fn tree_source(&self) -> TreeSource {
+        TreeSource {
+            config_path: self.config.clone(),
+            index: self.index.clone(),
+            save_index: self.save_index.clone(),
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Tag plain-text files and write the results of each file to an output directory, skipping
+    /// files whose output already exists.
+    Tag {
+        #[arg(help = "A glob pattern of the files to tag, i.e. \"corpus/**/*.txt\"")]
+        files: String,
+        #[arg(short, long, help = "The directory the results are written to")]
+        output: String,
+        #[arg(short, long, value_enum, default_value_t = TagFormat::Jsonl)]
+        format: TagFormat,
+        #[arg(
+            short,
+            long,
+            help = "The number of parallel workers, defaults to the number of cores"
+        )]
+        jobs: Option<usize>,
+        #[arg(
+            long,
+            help = "The options of /v2/process as JSON, i.e. '{\"max_gap\": \"1\"}'"
+        )]
+        options: Option<String>,
+    },
+    /// Tag documents read from stdin, one per line, and write the results of each document to
+    /// stdout as a line of JSON.
+    Pipe {
+        #[arg(
+            long,
+            help = "Read lines of raw text instead of JSON documents with an id, text and options"
+        )]
+        raw: bool,
+        #[arg(
+            long,
+            help = "The options of /v2/process as JSON, used for documents without options"
+        )]
+        options: Option<String>,
+    },
+}
+
+fn parse_options(options: Option<&str>) -> anyhow::Result<ProcessOptions> {
+    options.map_or_else(
+        || Ok(ProcessOptions::default()),
+        |options| serde_json::from_str(options).context("Invalid options"),
+    )
+}
+
+fn main() -> anyhow::Result<()> {
     let args = Args::parse();
+    // logs to stderr, so that the output of `pipe` on stdout only holds results
+    env_logger::init_from_env(env_logger::Env::new().default_filter_or(LOG_LEVEL));
+
+    match &args.command {
+        None => serve(args),
+        Some(Command::Tag {
+            files,
+            output,
+            format,
+            jobs,
+            options,
+        }) => {
+            let options = parse_options(options.as_deref())?;
+            let tree = args.tree_source().load()?;
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(jobs.unwrap_or_default())
+                .build()?;
+            let summary =
+                pool.install(|| tag::tag_files(&tree, files, output, *format, &options))?;
+            println!("Finished tagging files: {summary}");
+            if summary.failed > 0 {
+                anyhow::bail!("Failed to tag {} files", summary.failed);
+            }
+            Ok(())
+        }
+        Some(Command::Pipe { raw, options }) => {
+            let options = parse_options(options.as_deref())?;
+            let tree = args.tree_source().load()?;
+            let summary = tag::tag_lines(
+                &tree,
+                io::stdin().lock(),
+                io::stdout().lock(),
+                *raw,
+                &options,
+            )?;
+            eprintln!("Finished tagging documents: {summary}");
+            if summary.failed > 0 {
+                anyhow::bail!("Failed to tag {} documents", summary.failed);
+            }
+            Ok(())
+        }
+    }
+}
 
+#[actix_web::main]
+async fn serve(args: Args) -> anyhow::Result<()> {
     let accept_all = |_| true;
     let json_config = web::JsonConfig::default()
         .content_type_required(false)
         .content_type(accept_all)
         .limit(args.limit);
 
-    env_logger::init_from_env(env_logger::Env::new().default_filter_or(LOG_LEVEL));
+    let state: Arc<AppState> = Arc::new(AppState::new(
+        args.tree_source().load()?,
+        args.tree_source(),
+        args.admin_token.clone(),
+    ));
+
+    #[cfg(unix)]
+    {
+        let state = state.clone();
+        let mut hangup = signal(SignalKind::hangup())?;
+        actix_web::rt::spawn(async move {
+            while hangup.recv().await.is_some() {
+                log::info!("Received SIGHUP, reloading gazetteer");
+                if !state.reload() {
+                    log::warn!("A reload is already running");
+                }
+            }
+        });
+    }
 
-    let state: Arc<AppState> = Arc::new(AppState {
-        tree: parse_args_and_build_tree(&args.config)?,
-    });
     let data: web::Data<Arc<AppState>> = web::Data::new(state);
+    // shared by all workers, so that the permits of batches are shared as well
+    let batch_limit = web::Data::new(api::BatchLimit::new(args.limit));
 
     HttpServer::new(move || {
         let app = App::new()
@@ -186,9 +206,27 @@ async fn main() -> anyhow::Result<()> {
                     )
                     .route(web::post().to(api::v1_process)),
             )
+            .service(
+                web::resource("/v2/process")
+                    .wrap(
+                        actix_web::middleware::DefaultHeaders::default()
+                            .add(("Content-Type", "application/json")),
+                    )
+                    .route(web::post().to(api::v2_process)),
+            )
+            .service(
+                web::resource("/v2/batch")
+                    .app_data(batch_limit.clone())
+                    .route(web::post().to(api::v2_batch)),
+            )
             .service(
                 web::resource("/v1/communication_layer")
                     .route(web::get().to(api::v1_communication_layer)),
+            )
+            .service(
+                web::resource("/admin/reload")
+                    .route(web::get().to(api::admin_reload_status))
+                    .route(web::post().to(api::admin_reload)),
             );
 
         #[cfg(feature = "gui")]
@@ -0,0 +1,212 @@
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
+
+use anyhow::Context;
+use csv::StringRecord;
+use itertools::Itertools;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::util::Column;
+
+/// The number of rows dropped by each row filter, as `(filter, count)`.
+pub type DroppedRows = Vec<(String, usize)>;
+
+/// A predicate on a column of the input table, or a group of row filters of which any or all
+/// must be satisfied, i.e. `{ any = [...] }`. Rows that do not satisfy all row filters of their
+/// corpus are dropped while reading it.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum RowFilter {
+    Column {
+        column: Column,
+        #[serde(flatten)]
+        predicate: Predicate,
+    },
+    Any {
+        any: Vec<RowFilter>,
+    },
+    All {
+        all: Vec<RowFilter>,
+    },
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Predicate {
+    /// The value equals the given string.
+    Equals(String),
+    /// The value is one of the given strings.
+    In(Vec<String>),
+    /// The value matches the given regular expression.
+    Regex(String),
+    /// The value is empty (false) or non-empty (true).
+    NonEmpty(bool),
+}
+
+impl Display for RowFilter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let (column, predicate) = match self {
+            RowFilter::Column { column, predicate } => (column, predicate),
+            RowFilter::Any { any } => return write!(f, "({})", any.iter().join(" or ")),
+            RowFilter::All { all } => return write!(f, "({})", all.iter().join(" and ")),
+        };
+        let column = match column {
+            Column::Index(idx) => idx.to_string(),
+            Column::Header(header) => header.clone(),
+        };
+        match predicate {
+            Predicate::Equals(value) => write!(f, "{column} == {value:?}"),
+            Predicate::In(values) => write!(f, "{column} in {values:?}"),
+            Predicate::Regex(pattern) => write!(f, "{column} =~ /{pattern}/"),
+            Predicate::NonEmpty(true) => write!(f, "{column} is non-empty"),
+            Predicate::NonEmpty(false) => write!(f, "{column} is empty"),
+        }
+    }
+}
+
+pub(crate) enum CompiledPredicate {
+    Equals(String),
+    In(HashSet<String>),
+    Regex(Regex),
+    NonEmpty(bool),
+}
+
+/// A row filter with its columns resolved and its predicates compiled, see `RowFilter`.
+pub(crate) enum CompiledRowFilter {
+    Column {
+        idx: usize,
+        predicate: CompiledPredicate,
+    },
+    Any(Vec<CompiledRowFilter>),
+    All(Vec<CompiledRowFilter>),
+}
+
+impl CompiledRowFilter {
+    pub(crate) fn new(filter: &RowFilter, headers: Option<&StringRecord>) -> anyhow::Result<Self> {
+        let compile = |filters: &[RowFilter]| -> anyhow::Result<Vec<CompiledRowFilter>> {
+            filters
+                .iter()
+                .map(|filter| CompiledRowFilter::new(filter, headers))
+                .collect()
+        };
+        let (column, predicate) = match filter {
+            RowFilter::Column { column, predicate } => (column, predicate),
+            RowFilter::Any { any } => return Ok(CompiledRowFilter::Any(compile(any)?)),
+            RowFilter::All { all } => return Ok(CompiledRowFilter::All(compile(all)?)),
+        };
+        let (idx, _) = column.resolve(headers)?;
+        let predicate = match predicate {
+            Predicate::Equals(value) => CompiledPredicate::Equals(value.clone()),
+            Predicate::In(values) => CompiledPredicate::In(values.iter().cloned().collect()),
+            Predicate::Regex(pattern) => CompiledPredicate::Regex(
+                Regex::new(pattern)
+                    .with_context(|| format!("Invalid row filter regex '{pattern}'"))?,
+            ),
+            Predicate::NonEmpty(non_empty) => CompiledPredicate::NonEmpty(*non_empty),
+        };
+        Ok(CompiledRowFilter::Column { idx, predicate })
+    }
+
+    /// Whether the row satisfies the filter. Missing columns are treated as empty.
+    pub(crate) fn accepts(&self, row: &StringRecord) -> bool {
+        let (idx, predicate) = match self {
+            CompiledRowFilter::Column { idx, predicate } => (*idx, predicate),
+            CompiledRowFilter::Any(filters) => return filters.iter().any(|f| f.accepts(row)),
+            CompiledRowFilter::All(filters) => return filters.iter().all(|f| f.accepts(row)),
+        };
+        let value = row.get(idx).unwrap_or_default();
+        match predicate {
+            CompiledPredicate::Equals(expected) => value == expected,
+            CompiledPredicate::In(values) => values.contains(value),
+            CompiledPredicate::Regex(regex) => regex.is_match(value),
+            CompiledPredicate::NonEmpty(non_empty) => value.is_empty() != *non_empty,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_row_filter() {
+        let filters: Vec<RowFilter> = toml::from_str::<toml::Table>(
+            r#"
+            filters = [
+                { column = "taxonomicStatus", equals = "ACCEPTED" },
+                { column = 2, in = ["Animalia", "Plantae"] },
+                { column = "name", regex = "^[A-Z][a-z]+ [a-z]+$" },
+                { column = 3, non_empty = true },
+            ]
+            "#,
+        )
+        .unwrap()["filters"]
+            .clone()
+            .try_into()
+            .unwrap();
+        assert_eq!(filters[0].to_string(), r#"taxonomicStatus == "ACCEPTED""#);
+        assert!(matches!(
+            &filters[3],
+            RowFilter::Column {
+                predicate: Predicate::NonEmpty(true),
+                ..
+            }
+        ));
+
+        let headers = StringRecord::from(vec!["name", "taxonomicStatus", "kingdom", "rank"]);
+        let filters: Vec<CompiledRowFilter> = filters
+            .iter()
+            .map(|filter| CompiledRowFilter::new(filter, Some(&headers)).unwrap())
+            .collect();
+        let accepted = |row: Vec<&str>| {
+            let row = StringRecord::from(row);
+            filters
+                .iter()
+                .map(|filter| filter.accepts(&row))
+                .collect::<Vec<bool>>()
+        };
+        assert_eq!(
+            accepted(vec!["Puffinus puffinus", "ACCEPTED", "Animalia", "SPECIES"]),
+            vec![true, true, true, true]
+        );
+        assert_eq!(
+            accepted(vec!["Puffinus", "SYNONYM", "Fungi", ""]),
+            vec![false, false, false, false]
+        );
+        assert_eq!(
+            accepted(vec!["Puffinus puffinus"]),
+            vec![false, false, true, false]
+        );
+    }
+    #[test]
+    fn test_row_filter_groups() {
+        let filter: RowFilter = toml::from_str::<toml::Table>(
+            r#"
+            filter = { any = [
+                { column = "taxonomicStatus", equals = "ACCEPTED" },
+                { all = [
+                    { column = "kingdom", in = ["Animalia", "Plantae"] },
+                    { column = "rank", non_empty = true },
+                ] },
+            ] }
+            "#,
+        )
+        .unwrap()["filter"]
+            .clone()
+            .try_into()
+            .unwrap();
+        assert_eq!(
+            filter.to_string(),
+            r#"(taxonomicStatus == "ACCEPTED" or (kingdom in ["Animalia", "Plantae"] and rank is non-empty))"#
+        );
+
+        let headers = StringRecord::from(vec!["taxonomicStatus", "kingdom", "rank"]);
+        let filter = CompiledRowFilter::new(&filter, Some(&headers)).unwrap();
+        let accepts = |row: Vec<&str>| filter.accepts(&StringRecord::from(row));
+        assert!(accepts(vec!["ACCEPTED", "Fungi", ""]));
+        assert!(accepts(vec!["SYNONYM", "Plantae", "SPECIES"]));
+        assert!(!accepts(vec!["SYNONYM", "Plantae", ""]));
+        assert!(!accepts(vec!["SYNONYM", "Fungi", "SPECIES"]));
+    }
+}
@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+/// How skip-gram matches are found for a corpus.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SkipGramStrategy {
+    /// All skip-grams are generated when loading the corpus and inserted into the tree.
+    #[default]
+    Index,
+    /// The tree only holds the entries themselves. Skip-grams are found at query time by walking
+    /// past up to `max_skips` tree tokens that do not occur in the text.
+    Query,
+}
+
+/// Which tokens of an entry may be skipped.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SkipGramPositions {
+    /// Any token but the first.
+    #[default]
+    KeepFirst,
+    /// Any token but the first and the last, so that a skip-gram never ends early.
+    Interior,
+}
+
+/// Per-corpus restrictions on the generated skip-grams, applied with both strategies.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SkipGramPolicy {
+    pub positions: SkipGramPositions,
+    /// If given, at most this many variants are generated per entry, preferring fewer skipped
+    /// tokens and then the leftmost skipped positions.
+    pub max_variants: Option<usize>,
+    /// If true, skip-grams whose key is the `Full` key of an entry with a different label are
+    /// dropped.
+    pub drop_collisions: bool,
+}
+
+impl SkipGramPolicy {
+    /// The positions that may be skipped in an entry with `length` tokens.
+    fn skippable(&self, length: usize) -> std::ops::Range<usize> {
+        match self.positions {
+            SkipGramPositions::KeepFirst => 1..length,
+            SkipGramPositions::Interior => 1..length.saturating_sub(1).max(1),
+        }
+    }
+
+    /// Whether all `skipped` positions of an entry with `length` tokens may be skipped.
+    #[must_use]
+    pub fn allows(&self, length: usize, skipped: &[usize]) -> bool {
+        let skippable = self.skippable(length);
+        skipped.iter().all(|position| skippable.contains(position))
+    }
+
+    /// Whether the variant of an entry with `length` tokens that skips the given (ascending and
+    /// allowed) positions is within `max_variants`. Variants are ranked by the number of skipped
+    /// tokens and then lexicographically by their positions.
+    #[must_use]
+    pub fn within_max_variants(&self, length: usize, skipped: &[usize]) -> bool {
+        self.max_variants
+            .is_none_or(|max_variants| variant_rank(self.skippable(length), skipped) < max_variants)
+    }
+}
+
+/// The rank of the combination of `skipped` positions among all combinations of the `skippable`
+/// positions, ordered by size and then lexicographically.
+fn variant_rank(skippable: std::ops::Range<usize>, skipped: &[usize]) -> usize {
+    let n = skippable.len();
+    let k = skipped.len();
+    let mut rank: usize = (1..k).map(|size| binomial(n, size)).sum();
+    let mut next = 0;
+    for (i, position) in skipped.iter().enumerate() {
+        let idx = position - skippable.start;
+        for smaller in next..idx {
+            rank = rank.saturating_add(binomial(n - 1 - smaller, k - 1 - i));
+        }
+        next = idx + 1;
+    }
+    rank
+}
+
+fn binomial(n: usize, k: usize) -> usize {
+    if k > n {
+        return 0;
+    }
+    (0..k.min(n - k)).fold(1usize, |acc, i| acc.saturating_mul(n - i) / (i + 1))
+}
+
+/// Per-corpus settings for skip-grams found at query time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SkipGramOptions {
+    /// Skip-grams are never shorter than this.
+    pub min_length: i32,
+    /// The maximum number of tokens skipped in an entry.
+    pub max_skips: i32,
+}
+
+impl SkipGramOptions {
+    /// Whether skipping `skips` tokens of an entry with `length` tokens yields a skip-gram that
+    /// would also be generated at index time, see `util::create_skip_grams`.
+    #[must_use]
+    pub fn accepts(&self, length: usize, skips: usize) -> bool {
+        let (length, skips) = (length as i32, skips as i32);
+        skips > 0 && skips <= self.max_skips && length - skips >= self.min_length
+    }
+}
+
+/// The corpora whose skip-grams are found at query time.
+#[derive(Debug, Default)]
+pub(crate) struct LazySkipGrams {
+    pub(crate) corpora: HashMap<String, SkipGramOptions>,
+    /// The largest `max_skips` of all corpora.
+    pub(crate) max_skips: usize,
+}
+
+impl LazySkipGrams {
+    pub(crate) fn is_enabled(&self) -> bool {
+        !self.corpora.is_empty()
+    }
+
+    pub(crate) fn add_corpus(&mut self, corpus: &str, options: SkipGramOptions) {
+        self.max_skips = self.max_skips.max(options.max_skips.max(0) as usize);
+        self.corpora.insert(String::from(corpus), options);
+    }
+
+    /// Whether the `Full` match of an entry with `length` tokens may be reported as a skip-gram
+    /// after skipping `skips` of its tokens.
+    pub(crate) fn accepts(&self, corpus: Option<&str>, length: usize, skips: usize) -> bool {
+        corpus
+            .and_then(|corpus| self.corpora.get(corpus))
+            .is_some_and(|options| options.accepts(length, skips))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use itertools::Itertools;
+
+    use super::*;
+
+    #[test]
+    fn test_variant_rank() {
+        // all combinations of up to three of the positions 1..5, by size and lexicographically
+        let variants: Vec<Vec<usize>> =
+            (1..=3).flat_map(|size| (1..5).combinations(size)).collect();
+        for (rank, skipped) in variants.iter().enumerate() {
+            assert_eq!(variant_rank(1..5, skipped), rank);
+        }
+
+        let policy = SkipGramPolicy {
+            positions: SkipGramPositions::Interior,
+            max_variants: Some(3),
+            drop_collisions: false,
+        };
+        assert!(policy.allows(5, &[1, 3]));
+        assert!(!policy.allows(5, &[4]));
+        assert!(policy.within_max_variants(5, &[3]));
+        assert!(!policy.within_max_variants(5, &[1, 2]));
+    }
+}
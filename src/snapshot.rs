@@ -0,0 +1,461 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+
+use crate::fuzzy::FuzzyOptions;
+use crate::skip_gram::{SkipGramOptions, SkipGramPolicy};
+use crate::tree::{HashMapSearchTree, Match, MatchType, Provenance, ScoreWeights};
+use crate::util::{Metadata, Tokenizer};
+
+/// Magic bytes at the start of every snapshot file.
+const SNAPSHOT_MAGIC: &[u8; 8] = b"GZTRSNAP";
+/// Version of the binary snapshot layout. Bump whenever `SnapshotHeader` or `TreeData` change.
+const SNAPSHOT_FORMAT_VERSION: u32 = 14;
+
+/// Describes how a snapshot was built. It is written in front of the tree data so that
+/// mismatching snapshots can be rejected without deserializing the whole tree.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SnapshotHeader {
+    pub format_version: u32,
+    /// The version of the crate that wrote the snapshot.
+    pub crate_version: String,
+    /// The hash of the configuration the tree was built from, see `config::config_hash`.
+    pub config_hash: u64,
+    /// The raw configuration TOML the tree was built from.
+    pub config: String,
+}
+
+/// A match as `(match_type, match_string, match_label, skipped, provenances)` with the strings
+/// and the provenances given as interned ids.
+type MatchData = (MatchType, u32, u32, Vec<u32>, Vec<u32>);
+
+/// A provenance as `(corpus, source, row, metadata, synonym)` with the strings and the metadata
+/// given as interned ids.
+type ProvenanceData = (u32, u32, u64, u32, bool);
+
+/// The metadata of a row as `(key, value)` pairs of interned ids.
+type MetadataData = Vec<(u32, u32)>;
+
+/// The serialized form of a `HashMapSearchTree`. Match strings, labels and provenances are
+/// interned so that shared `Arc`s stay shared after loading.
+#[derive(Serialize, Deserialize)]
+struct TreeData {
+    /// The tokenizer, serialized as JSON as its wrappers rely on self-describing formats.
+    tokenizer: String,
+    /// The token vocabulary, ordered by token id.
+    vocabulary: Vec<String>,
+    strings: Vec<String>,
+    /// The metadata of the rows, shared by the provenances of all names of a row.
+    metadata: Vec<MetadataData>,
+    /// The provenances, see `ProvenanceData`.
+    provenances: Vec<ProvenanceData>,
+    /// The nodes of the tree in id order as `(parent, token, matches)`.
+    nodes: Vec<(u32, u32, Vec<MatchData>)>,
+    /// The fuzzy-enabled corpora. The fuzzy index itself is rebuilt when loading.
+    fuzzy: Vec<(String, FuzzyOptions)>,
+    /// The corpora with query-time skip-grams.
+    lazy_skip_grams: Vec<(String, SkipGramOptions)>,
+    skip_gram_policies: Vec<(String, SkipGramPolicy)>,
+    score_weights: ScoreWeights,
+    /// See `HashMapSearchTree::max_gap`.
+    max_gap: usize,
+}
+
+#[derive(Default)]
+struct Interner<'a> {
+    ids: HashMap<&'a str, u32>,
+    strings: Vec<String>,
+}
+
+impl<'a> Interner<'a> {
+    fn intern(&mut self, string: &'a str) -> u32 {
+        if let Some(id) = self.ids.get(string) {
+            return *id;
+        }
+        let id = self.strings.len() as u32;
+        self.ids.insert(string, id);
+        self.strings.push(String::from(string));
+        id
+    }
+}
+
+impl TreeData {
+    fn from_tree(tree: &HashMapSearchTree) -> anyhow::Result<Self> {
+        let mut vocabulary = vec![String::new(); tree.vocabulary.len()];
+        for (token, id) in &tree.vocabulary {
+            vocabulary[*id as usize].clone_from(token);
+        }
+
+        let mut interner = Interner::default();
+        let mut provenance_index: HashMap<*const Provenance, u32> = HashMap::new();
+        let mut provenances = Vec::new();
+        let mut metadata_index: HashMap<*const (), u32> = HashMap::new();
+        let mut metadata = Vec::new();
+        let nodes = tree
+            .nodes
+            .iter()
+            .map(|node| {
+                let matches = node
+                    .matches
+                    .iter()
+                    .map(|mtch| {
+                        let provenance_ids: Vec<u32> = mtch
+                            .provenances
+                            .iter()
+                            .map(|provenance| {
+                                *provenance_index
+                                    .entry(Arc::as_ptr(provenance))
+                                    .or_insert_with(|| {
+                                        let metadata_id = *metadata_index
+                                            .entry(Arc::as_ptr(&provenance.metadata).cast())
+                                            .or_insert_with(|| {
+                                                metadata.push(
+                                                    provenance
+                                                        .metadata
+                                                        .iter()
+                                                        .map(|(key, value)| {
+                                                            (
+                                                                interner.intern(key),
+                                                                interner.intern(value),
+                                                            )
+                                                        })
+                                                        .collect(),
+                                                );
+                                                metadata.len() as u32 - 1
+                                            });
+                                        provenances.push((
+                                            interner.intern(&provenance.corpus),
+                                            interner.intern(&provenance.source),
+                                            provenance.row,
+                                            metadata_id,
+                                            provenance.synonym,
+                                        ));
+                                        provenances.len() as u32 - 1
+                                    })
+                            })
+                            .collect();
+                        (
+                            mtch.match_type.clone(),
+                            interner.intern(&mtch.match_string),
+                            interner.intern(&mtch.match_label),
+                            mtch.skipped.to_vec(),
+                            provenance_ids,
+                        )
+                    })
+                    .collect();
+                (node.parent, node.token, matches)
+            })
+            .collect();
+        Ok(TreeData {
+            tokenizer: serde_json::to_string(&tree.tokenizer)
+                .context("Failed to serialize tokenizer")?,
+            vocabulary,
+            strings: interner.strings,
+            metadata,
+            provenances,
+            nodes,
+            fuzzy: tree
+                .fuzzy
+                .corpora
+                .iter()
+                .map(|(corpus, options)| (corpus.clone(), *options))
+                .collect(),
+            lazy_skip_grams: tree
+                .lazy_skip_grams
+                .corpora
+                .iter()
+                .map(|(corpus, options)| (corpus.clone(), *options))
+                .collect(),
+            skip_gram_policies: tree
+                .skip_gram_policies
+                .iter()
+                .map(|(corpus, policy)| (corpus.clone(), *policy))
+                .collect(),
+            score_weights: tree.score_weights,
+            max_gap: tree.max_gap,
+        })
+    }
+
+    fn into_tree(self) -> anyhow::Result<HashMapSearchTree> {
+        let strings: Vec<Arc<String>> = self.strings.into_iter().map(Arc::new).collect();
+        let get = |id: u32| {
+            strings
+                .get(id as usize)
+                .cloned()
+                .context("Snapshot references an unknown string")
+        };
+        // metadata strings are shared as `Arc<str>`, created once per string
+        let mut shared: HashMap<u32, Arc<str>> = HashMap::new();
+        let mut get_shared = |id: u32| -> anyhow::Result<Arc<str>> {
+            if let Some(string) = shared.get(&id) {
+                return Ok(string.clone());
+            }
+            let string: Arc<str> = Arc::from(get(id)?.as_str());
+            shared.insert(id, string.clone());
+            Ok(string)
+        };
+        let metadata = self
+            .metadata
+            .into_iter()
+            .map(|metadata| {
+                metadata
+                    .into_iter()
+                    .map(|(key, value)| Ok((get_shared(key)?, get_shared(value)?)))
+                    .collect::<anyhow::Result<Metadata>>()
+            })
+            .collect::<anyhow::Result<Vec<Metadata>>>()?;
+        let provenances = self
+            .provenances
+            .into_iter()
+            .map(|(corpus, source, row, metadata_id, synonym)| {
+                Ok(Arc::new(Provenance {
+                    corpus: get(corpus)?,
+                    source: get(source)?,
+                    row,
+                    metadata: metadata
+                        .get(metadata_id as usize)
+                        .cloned()
+                        .context("Snapshot references unknown metadata")?,
+                    synonym,
+                }))
+            })
+            .collect::<anyhow::Result<Vec<Arc<Provenance>>>>()?;
+        let get_provenance = |id: u32| {
+            provenances
+                .get(id as usize)
+                .cloned()
+                .context("Snapshot references an unknown provenance")
+        };
+        let parts = self
+            .nodes
+            .into_iter()
+            .map(|(parent, token, matches)| {
+                let matches = matches
+                    .into_iter()
+                    .map(
+                        |(match_type, match_string, match_label, skipped, provenances)| {
+                            Ok(Match {
+                                match_type,
+                                match_string: get(match_string)?,
+                                match_label: get(match_label)?,
+                                skipped: skipped.into_boxed_slice(),
+                                provenances: provenances
+                                    .into_iter()
+                                    .map(get_provenance)
+                                    .collect::<anyhow::Result<_>>()?,
+                            })
+                        },
+                    )
+                    .collect::<anyhow::Result<Vec<Match>>>()?;
+                Ok((parent, token, matches))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let vocabulary = self
+            .vocabulary
+            .into_iter()
+            .enumerate()
+            .map(|(id, token)| (token, id as u32))
+            .collect();
+        let tokenizer: Tokenizer =
+            serde_json::from_str(&self.tokenizer).context("Failed to deserialize tokenizer")?;
+        let mut tree = HashMapSearchTree::from_parts(vocabulary, parts, tokenizer)?;
+        for (corpus, options) in self.fuzzy {
+            tree.enable_fuzzy(&corpus, options);
+        }
+        for (corpus, options) in self.lazy_skip_grams {
+            tree.enable_lazy_skip_grams(&corpus, options);
+        }
+        for (corpus, policy) in self.skip_gram_policies {
+            tree.set_skip_gram_policy(&corpus, policy);
+        }
+        tree.set_score_weights(self.score_weights);
+        tree.set_max_gap(self.max_gap);
+        Ok(tree)
+    }
+}
+
+fn is_gzip(path: &str) -> bool {
+    Path::new(path)
+        .extension()
+        .is_some_and(|extension| extension == "gz")
+}
+
+/// Writes a snapshot of the given tree to `path`. If the path ends in `.gz`, the snapshot is
+/// compressed.
+pub fn save(
+    tree: &HashMapSearchTree,
+    path: &str,
+    config_hash: u64,
+    config: &str,
+) -> anyhow::Result<()> {
+    let file = File::create(path).context(format!("Could not create snapshot file {path}"))?;
+    let mut writer: Box<dyn Write> = if is_gzip(path) {
+        Box::new(GzEncoder::new(BufWriter::new(file), Compression::default()))
+    } else {
+        Box::new(BufWriter::new(file))
+    };
+
+    let header = SnapshotHeader {
+        format_version: SNAPSHOT_FORMAT_VERSION,
+        crate_version: String::from(env!("CARGO_PKG_VERSION")),
+        config_hash,
+        config: String::from(config),
+    };
+    writer.write_all(SNAPSHOT_MAGIC)?;
+    bincode::serialize_into(&mut writer, &header).context("Failed to write snapshot header")?;
+    bincode::serialize_into(&mut writer, &TreeData::from_tree(tree)?)
+        .context("Failed to write snapshot data")?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Loads a tree snapshot from `path`. Refuses snapshots that were written by a different crate
+/// version, in a different snapshot format or from a configuration with a different hash.
+pub fn load(path: &str, config_hash: u64) -> anyhow::Result<HashMapSearchTree> {
+    let file = File::open(path).context(format!("Could not open snapshot file {path}"))?;
+    let mut reader: Box<dyn Read> = if is_gzip(path) {
+        Box::new(GzDecoder::new(BufReader::new(file)))
+    } else {
+        Box::new(BufReader::new(file))
+    };
+
+    let mut magic = [0u8; 8];
+    reader
+        .read_exact(&mut magic)
+        .context("Failed to read snapshot header")?;
+    if &magic != SNAPSHOT_MAGIC {
+        return Err(anyhow!("{path} is not a gazetteer snapshot"));
+    }
+
+    let header: SnapshotHeader =
+        bincode::deserialize_from(&mut reader).context("Failed to read snapshot header")?;
+    if header.format_version != SNAPSHOT_FORMAT_VERSION {
+        return Err(anyhow!(
+            "Snapshot format version {} does not match the expected version {}",
+            header.format_version,
+            SNAPSHOT_FORMAT_VERSION
+        ));
+    }
+    if header.crate_version != env!("CARGO_PKG_VERSION") {
+        return Err(anyhow!(
+            "Snapshot was written by gazetteer {} but this is gazetteer {}",
+            header.crate_version,
+            env!("CARGO_PKG_VERSION")
+        ));
+    }
+    if header.config_hash != config_hash {
+        return Err(anyhow!(
+            "Snapshot was built from a different configuration (hash {:016x}, expected {:016x})",
+            header.config_hash,
+            config_hash
+        ));
+    }
+
+    let data: TreeData =
+        bincode::deserialize_from(&mut reader).context("Failed to read snapshot data")?;
+    data.into_tree()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tree::SearchOptions;
+    use crate::util::TempFile;
+
+    #[test]
+    fn test_round_trip() {
+        let mut tree = HashMapSearchTree::default();
+        let entries: Vec<(String, String)> = vec![
+            ("An example".to_string(), "uri:example".to_string()),
+            ("An example phrase".to_string(), "uri:phrase".to_string()),
+            ("Another example A".to_string(), "uri:other".to_string()),
+        ];
+        tree.load(entries, true, 2, 2, true, 0, 3);
+        let provenance = Arc::new(Provenance {
+            corpus: Arc::new("corpus".to_string()),
+            source: Arc::new("corpus.tsv".to_string()),
+            row: 7,
+            metadata: Arc::from([(Arc::from("rank"), Arc::from("SPECIES"))]),
+            synonym: true,
+        });
+        // another name of the same row
+        let primary = Arc::new(Provenance {
+            synonym: false,
+            ..(*provenance).clone()
+        });
+        tree.load_with_provenance(
+            vec![
+                (
+                    "An example".to_string(),
+                    "uri:other-example".to_string(),
+                    Some(provenance),
+                ),
+                (
+                    "Primary example".to_string(),
+                    "uri:other-example".to_string(),
+                    Some(primary),
+                ),
+            ],
+            false,
+            0,
+            0,
+            false,
+            0,
+            3,
+        );
+
+        tree.enable_fuzzy(
+            "corpus",
+            FuzzyOptions {
+                max_distance: 1,
+                min_token_length: 5,
+            },
+        );
+
+        let file = TempFile::new("snapshot.bin.gz", "");
+        save(&tree, file.path(), 42, "").unwrap();
+
+        assert!(load(file.path(), 43).is_err());
+        let loaded = load(file.path(), 42).unwrap();
+
+        assert_eq!(loaded.tree_depth, tree.tree_depth);
+        assert_eq!(loaded.len(), tree.len());
+        assert_eq!(loaded.vocabulary, tree.vocabulary);
+        assert_eq!(loaded.corpora(), tree.corpora());
+        assert_eq!(
+            loaded.get(&["an".to_string(), "example".to_string()]),
+            tree.get(&["an".to_string(), "example".to_string()])
+        );
+        // the metadata of a row stays shared by all of its names
+        let metadata = |key: [&str; 2]| {
+            let matches = loaded.get(&key.map(String::from)).unwrap();
+            let mtch = matches
+                .iter()
+                .find(|mtch| *mtch.match_label == "uri:other-example")
+                .unwrap();
+            mtch.provenances[0].metadata.clone()
+        };
+        assert!(Arc::ptr_eq(
+            &metadata(["an", "example"]),
+            &metadata(["primary", "example"])
+        ));
+        let fuzzy_results = loaded.search("An exsample", &SearchOptions::default());
+        assert!(!fuzzy_results.is_empty());
+        assert_eq!(
+            fuzzy_results,
+            tree.search("An exsample", &SearchOptions::default())
+        );
+        assert_eq!(
+            loaded.search("An example phrase", &SearchOptions::default()),
+            tree.search("An example phrase", &SearchOptions::default())
+        );
+    }
+}
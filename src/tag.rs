@@ -0,0 +1,391 @@
+use std::fs::File;
+use std::io::{BufRead, BufWriter, Write};
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::Context;
+use indicatif::{ProgressBar, ProgressStyle};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::api::{parse_line, process_v2, Document, DocumentError, ProcessOptions, SpanV2};
+use crate::tree::HashMapSearchTree;
+use crate::util::get_files;
+
+/// The format of the files written by `tag_files`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum TagFormat {
+    /// One span per line, as returned by `/v2/process`.
+    #[default]
+    Jsonl,
+    /// One match per row, with a header.
+    Tsv,
+}
+
+impl TagFormat {
+    #[must_use]
+    pub fn extension(self) -> &'static str {
+        match self {
+            TagFormat::Jsonl => "jsonl",
+            TagFormat::Tsv => "tsv",
+        }
+    }
+}
+
+/// The number of files tagged, skipped because their output already exists, and failed.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TagSummary {
+    pub tagged: usize,
+    pub skipped: usize,
+    pub failed: usize,
+}
+
+impl std::fmt::Display for TagSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} tagged, {} skipped, {} failed",
+            self.tagged, self.skipped, self.failed
+        )
+    }
+}
+
+/// Writes the spans of a document in the given format.
+pub fn write_spans<W: Write>(writer: W, spans: &[SpanV2], format: TagFormat) -> anyhow::Result<()> {
+    match format {
+        TagFormat::Jsonl => {
+            let mut writer = writer;
+            for span in spans {
+                serde_json::to_writer(&mut writer, span)?;
+                writer.write_all(b"\n")?;
+            }
+            writer.flush()?;
+        }
+        TagFormat::Tsv => {
+            let mut writer = csv::WriterBuilder::new()
+                .delimiter(b'\t')
+                .from_writer(writer);
+            writer.write_record([
+                "begin", "end", "text", "type", "label", "corpus", "entry", "score",
+            ])?;
+            for span in spans {
+                for mtch in &span.matches {
+                    writer.write_record([
+                        &span.begin.to_string(),
+                        &span.end.to_string(),
+                        &span.text,
+                        &mtch.match_type.to_string(),
+                        &mtch.label,
+                        &mtch.corpora.join(","),
+                        &mtch.entry,
+                        &format!("{:.4}", mtch.score),
+                    ])?;
+                }
+            }
+            writer.flush()?;
+        }
+    }
+    Ok(())
+}
+
+/// The leading directories of a glob pattern that contain no wildcards, i.e. `corpus/2024` for
+/// `corpus/2024/**/*.txt`.
+fn glob_base(pattern: &str) -> PathBuf {
+    let mut base = PathBuf::new();
+    let mut components = Path::new(pattern).components().peekable();
+    while let Some(component) = components.next() {
+        let is_glob = component
+            .as_os_str()
+            .to_string_lossy()
+            .contains(['*', '?', '[', ']']);
+        // the last component is the file name itself
+        if is_glob || components.peek().is_none() {
+            break;
+        }
+        base.push(component);
+    }
+    base
+}
+
+/// The output path of a file matched by the glob pattern: its path below the base of the pattern,
+/// inside the output directory and with the extension of the format appended.
+fn output_path(file: &str, base: &Path, output_dir: &Path, format: TagFormat) -> PathBuf {
+    let relative: PathBuf = Path::new(file)
+        .strip_prefix(base)
+        .unwrap_or(Path::new(file))
+        .components()
+        .filter(|component| matches!(component, Component::Normal(_)))
+        .collect();
+    let mut path = output_dir.join(relative).into_os_string();
+    path.push(".");
+    path.push(format.extension());
+    PathBuf::from(path)
+}
+
+fn tag_file(
+    tree: &HashMapSearchTree,
+    file: &str,
+    output: &Path,
+    format: TagFormat,
+    options: &ProcessOptions,
+) -> anyhow::Result<()> {
+    let text = std::fs::read_to_string(file).with_context(|| format!("Failed to read {file}"))?;
+    let spans = process_v2(tree, &text, options)?;
+
+    if let Some(parent) = output.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    // written to a temporary file first, so an interrupted run never leaves a partial output
+    // that is mistaken for a finished one and skipped when the run is resumed
+    let mut partial = output.as_os_str().to_owned();
+    partial.push(".partial");
+    let partial = PathBuf::from(partial);
+    let writer = BufWriter::new(File::create(&partial)?);
+    write_spans(writer, &spans, format)
+        .and_then(|()| Ok(std::fs::rename(&partial, output)?))
+        .inspect_err(|_| {
+            let _ = std::fs::remove_file(&partial);
+        })
+        .with_context(|| format!("Failed to write {}", output.display()))
+}
+
+/// Tags all files matched by the glob pattern in parallel and writes the results of each file to
+/// the output directory, mirroring the directories below the base of the pattern. Files whose
+/// output already exists are skipped, so interrupted runs can be resumed.
+pub fn tag_files(
+    tree: &HashMapSearchTree,
+    pattern: &str,
+    output_dir: &str,
+    format: TagFormat,
+    options: &ProcessOptions,
+) -> anyhow::Result<TagSummary> {
+    options.validate(tree)?;
+    let files = get_files(pattern);
+    let base = glob_base(pattern);
+    let output_dir = Path::new(output_dir);
+
+    let pb = ProgressBar::new(files.len() as u64);
+    pb.set_style(ProgressStyle::with_template("Tagging Files {bar:40} {pos}/{len} {msg}").unwrap());
+    let summary = files
+        .par_iter()
+        .map(|file| {
+            let output = output_path(file, &base, output_dir, format);
+            let summary = if output.exists() {
+                TagSummary {
+                    skipped: 1,
+                    ..Default::default()
+                }
+            } else if let Err(e) = tag_file(tree, file, &output, format, options) {
+                pb.println(format!("{e:#}"));
+                TagSummary {
+                    failed: 1,
+                    ..Default::default()
+                }
+            } else {
+                TagSummary {
+                    tagged: 1,
+                    ..Default::default()
+                }
+            };
+            pb.inc(1);
+            summary
+        })
+        .reduce(TagSummary::default, |a, b| TagSummary {
+            tagged: a.tagged + b.tagged,
+            skipped: a.skipped + b.skipped,
+            failed: a.failed + b.failed,
+        });
+    pb.finish_with_message(summary.to_string());
+    Ok(summary)
+}
+
+/// Tags the documents read from `input` one by one, as soon as each arrives, and writes the
+/// result of each document as a line of JSON to `output`. Each line of the input is a JSON
+/// document as in `/v2/batch`, or raw text if `raw` is set, identified by its line number.
+/// Documents without options use `options`. A line that cannot be read or processed is reported
+/// as an error record in place of its result, so one malformed line does not end the pipe.
+pub fn tag_lines<R: BufRead, W: Write>(
+    tree: &HashMapSearchTree,
+    mut input: R,
+    mut output: W,
+    raw: bool,
+    options: &ProcessOptions,
+) -> anyhow::Result<TagSummary> {
+    let mut summary = TagSummary::default();
+    let mut buffer = Vec::new();
+    for line_number in 1.. {
+        buffer.clear();
+        if input
+            .read_until(b'\n', &mut buffer)
+            .context("Failed to read input")?
+            == 0
+        {
+            break;
+        }
+        let line = buffer.strip_suffix(b"\n").unwrap_or(&buffer);
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let document = if raw {
+            Some(
+                String::from_utf8(line.to_vec())
+                    .with_context(|| format!("Invalid UTF-8 in line {line_number}"))
+                    .map(|text| Document {
+                        id: Value::from(line_number),
+                        text,
+                        options: None,
+                    }),
+            )
+        } else {
+            parse_line(line, line_number)
+        };
+        let result = match document {
+            None => {
+                summary.skipped += 1;
+                continue;
+            }
+            Some(Ok(document)) => document
+                .process(tree, options)
+                .map_err(|e| (Some(document.id), e)),
+            Some(Err(e)) => Err((None, e)),
+        };
+        match result {
+            Ok(result) => {
+                serde_json::to_writer(&mut output, &result)?;
+                summary.tagged += 1;
+            }
+            Err((id, e)) => {
+                let error = DocumentError {
+                    id,
+                    line: Some(line_number),
+                    error: format!("{e:#}"),
+                };
+                serde_json::to_writer(&mut output, &error)?;
+                summary.failed += 1;
+            }
+        }
+        output.write_all(b"\n")?;
+        // flushed per document, so the results stream through pipelines
+        output.flush()?;
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sula_tree;
+    use crate::util::{OffsetUnit, TempDir};
+
+    #[test]
+    fn test_output_path() {
+        let base = glob_base("corpus/2024/**/*.txt");
+        assert_eq!(base, PathBuf::from("corpus/2024"));
+        assert_eq!(
+            output_path(
+                "corpus/2024/a/b.txt",
+                &base,
+                Path::new("out"),
+                TagFormat::Tsv
+            ),
+            PathBuf::from("out/a/b.txt.tsv")
+        );
+        assert_eq!(glob_base("*.txt"), PathBuf::new());
+        assert_eq!(
+            output_path(
+                "b.txt",
+                &glob_base("*.txt"),
+                Path::new("out"),
+                TagFormat::Jsonl
+            ),
+            PathBuf::from("out/b.txt.jsonl")
+        );
+    }
+
+    #[test]
+    fn test_tag_files() {
+        let mut tree = HashMapSearchTree::default();
+        tree.load(
+            vec![("Sula bassana".to_string(), "uri:sula".to_string())],
+            false,
+            0,
+            0,
+            false,
+            0,
+            3,
+        );
+        let dir = TempDir::new("tag");
+        let input = dir.path().join("input");
+        std::fs::create_dir_all(input.join("nested")).unwrap();
+        std::fs::write(input.join("a.txt"), "Die Sula bassana brütet.").unwrap();
+        std::fs::write(input.join("nested/b.txt"), "Nichts").unwrap();
+        let pattern = format!("{}/**/*.txt", input.display());
+        let output = dir.path().join("output");
+        let output_dir = output.to_str().unwrap();
+
+        let options = ProcessOptions::default();
+        let summary = tag_files(&tree, &pattern, output_dir, TagFormat::Tsv, &options).unwrap();
+        assert_eq!(summary.tagged, 2);
+        assert_eq!(
+            std::fs::read_to_string(output.join("a.txt.tsv")).unwrap(),
+            "begin\tend\ttext\ttype\tlabel\tcorpus\tentry\tscore\n\
+             4\t16\tSula bassana\tFull\turi:sula\t\tSula bassana\t0.6667\n"
+        );
+        assert!(output.join("nested/b.txt.tsv").exists());
+
+        // existing outputs are skipped
+        let summary = tag_files(&tree, &pattern, output_dir, TagFormat::Tsv, &options).unwrap();
+        assert_eq!((summary.tagged, summary.skipped), (0, 2));
+    }
+
+    #[test]
+    fn test_tag_lines() {
+        let tree = sula_tree();
+        let lines = |output: Vec<u8>| -> Vec<Value> {
+            String::from_utf8(output)
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect()
+        };
+        let options = ProcessOptions::default();
+
+        let mut output = Vec::new();
+        let input = "Eine Sula\n\nNichts\n";
+        let summary = tag_lines(&tree, input.as_bytes(), &mut output, true, &options).unwrap();
+        assert_eq!((summary.tagged, summary.failed), (3, 0));
+        let output = lines(output);
+        assert_eq!(output[0]["id"], 1);
+        assert_eq!(output[0]["results"][0]["text"], "Sula");
+        assert_eq!(output[2]["results"].as_array().unwrap().len(), 0);
+
+        let mut output = Vec::new();
+        let input = "{\"id\": \"a\", \"text\": \"Die Sula\"}\n\n{\"id\": \"b\", \"text\": \"Sula\", \"options\": {\"offset_unit\": \"byte\"}}\n";
+        let options = ProcessOptions {
+            offset_unit: Some(OffsetUnit::Utf16),
+            ..Default::default()
+        };
+        let summary = tag_lines(&tree, input.as_bytes(), &mut output, false, &options).unwrap();
+        assert_eq!((summary.tagged, summary.skipped), (2, 1));
+        let output = lines(output);
+        assert_eq!(output[0]["id"], "a");
+        assert_eq!(output[0]["results"][0]["begin"], 4);
+        assert_eq!(output[1]["id"], "b");
+
+        // malformed and rejected documents are reported, the following ones are still tagged
+        let mut output = Vec::new();
+        let input = "Sula\n{\"id\": \"c\", \"text\": \"Sula\", \"options\": {\"max_gap\": \"9\"}}\n{\"id\": \"d\", \"text\": \"Sula\"}";
+        let summary = tag_lines(&tree, input.as_bytes(), &mut output, false, &options).unwrap();
+        assert_eq!((summary.tagged, summary.failed), (1, 2));
+        let output = lines(output);
+        assert_eq!(output[0]["line"], 1);
+        assert!(output[0].get("id").is_none());
+        assert!(output[0]["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("Invalid document in line 1"));
+        assert_eq!(output[1]["id"], "c");
+        assert_eq!(output[1]["line"], 2);
+        assert_eq!(output[1]["error"], "max_gap must be at most 3, got 9");
+        assert_eq!(output[2]["id"], "d");
+    }
+}
@@ -0,0 +1,230 @@
+use serde::{Deserialize, Serialize};
+
+/// How the subgenus of a name, i.e. "Puffinus (Puffinus) puffinus", appears in its variants.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SubgenusForm {
+    /// In parentheses after the genus, also for names that give it as "subgen. Puffinus".
+    Parenthesized,
+    /// Omitted.
+    Drop,
+    /// Both in parentheses and omitted.
+    #[default]
+    Both,
+}
+
+/// How the rank markers of infraspecific names, i.e. "Homo sapiens subsp. sapiens", appear in
+/// their variants.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RankMarkerForm {
+    /// Normalized to "subsp.", "var." and "f.", i.e. "ssp." becomes "subsp.".
+    Normalize,
+    /// Omitted, i.e. "Homo sapiens sapiens".
+    Remove,
+    /// Both normalized and omitted.
+    #[default]
+    Both,
+}
+
+/// Per-corpus settings for generating canonical variants of scientific names.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TaxonVariantOptions {
+    /// If true, authorships and years are removed, i.e. "Puffinus puffinus (Brünnich, 1764)"
+    /// becomes "Puffinus puffinus".
+    pub strip_authorship: bool,
+    pub subgenus: SubgenusForm,
+    pub rank_markers: RankMarkerForm,
+}
+
+impl Default for TaxonVariantOptions {
+    fn default() -> Self {
+        TaxonVariantOptions {
+            strip_authorship: true,
+            subgenus: SubgenusForm::default(),
+            rank_markers: RankMarkerForm::default(),
+        }
+    }
+}
+
+/// Words that start an authorship even though they are lowercase, i.e. "de Candolle".
+const AUTHOR_PARTICLES: &[&str] = &[
+    "de", "del", "della", "der", "den", "van", "von", "da", "du", "la", "le", "ex", "et", "in",
+    "and", "&",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part<'a> {
+    Epithet(&'a str),
+    /// A rank marker, normalized and as written.
+    Rank(&'static str, &'a str),
+    Authorship(&'a str),
+}
+
+/// The normalized form of a rank marker, if the word is one.
+fn rank_marker(word: &str) -> Option<&'static str> {
+    match word.to_lowercase().as_str() {
+        "subsp." | "subsp" | "ssp." | "ssp" | "subspecies" => Some("subsp."),
+        "var." | "var" | "variety" => Some("var."),
+        "subvar." => Some("subvar."),
+        "f." | "fo." | "forma" => Some("f."),
+        "subf." => Some("subf."),
+        _ => None,
+    }
+}
+
+fn is_capitalized(word: &str) -> bool {
+    word.chars().next().is_some_and(char::is_uppercase)
+        && word.chars().skip(1).all(|c| c.is_lowercase() || c == '-')
+}
+
+fn is_epithet(word: &str) -> bool {
+    word == "×"
+        || (word.chars().count() > 1
+            && word.chars().all(|c| c.is_lowercase() || c == '-')
+            && !AUTHOR_PARTICLES.contains(&word))
+}
+
+/// All canonical variants of a scientific name, excluding the name itself. Names that do not
+/// start with a capitalized genus yield no variants.
+#[must_use]
+pub fn taxon_variants(name: &str, options: &TaxonVariantOptions) -> Vec<String> {
+    let words: Vec<&str> = name.split_whitespace().collect();
+    let Some(genus) = words.first().filter(|genus| is_capitalized(genus)) else {
+        return Vec::new();
+    };
+
+    let mut idx = 1;
+    let mut subgenus = None;
+    if let Some(word) = words.get(idx) {
+        if let Some(inner) = word.strip_prefix('(').and_then(|w| w.strip_suffix(')')) {
+            if is_capitalized(inner) {
+                subgenus = Some(inner);
+                idx += 1;
+            }
+        } else if matches!(*word, "subgen." | "subg.") {
+            if let Some(inner) = words.get(idx + 1).filter(|w| is_capitalized(w)) {
+                subgenus = Some(*inner);
+                idx += 2;
+            }
+        }
+    }
+
+    let mut parts: Vec<Part> = Vec::new();
+    let mut authorship = false;
+    for word in &words[idx..] {
+        if let Some(rank) = rank_marker(word) {
+            parts.push(Part::Rank(rank, word));
+            authorship = false;
+        } else if !authorship && is_epithet(word) {
+            parts.push(Part::Epithet(word));
+        } else {
+            parts.push(Part::Authorship(word));
+            authorship = true;
+        }
+    }
+    // a rank marker that is not followed by an epithet is part of an authorship, i.e. "L. f."
+    for i in 0..parts.len() {
+        if let Part::Rank(_, word) = parts[i] {
+            if !matches!(parts.get(i + 1), Some(Part::Epithet(_))) {
+                parts[i] = Part::Authorship(word);
+            }
+        }
+    }
+
+    let subgenus_forms: &[bool] = match (subgenus, options.subgenus) {
+        (None, _) | (Some(_), SubgenusForm::Drop) => &[false],
+        (Some(_), SubgenusForm::Parenthesized) => &[true],
+        (Some(_), SubgenusForm::Both) => &[true, false],
+    };
+    let has_rank = parts.iter().any(|part| matches!(part, Part::Rank(..)));
+    let rank_forms: &[bool] = match (has_rank, options.rank_markers) {
+        (false, _) | (true, RankMarkerForm::Normalize) => &[true],
+        (true, RankMarkerForm::Remove) => &[false],
+        (true, RankMarkerForm::Both) => &[true, false],
+    };
+
+    let mut variants: Vec<String> = Vec::new();
+    for with_subgenus in subgenus_forms {
+        for with_ranks in rank_forms {
+            let mut variant: Vec<String> = vec![String::from(*genus)];
+            if let (true, Some(subgenus)) = (with_subgenus, subgenus) {
+                variant.push(format!("({subgenus})"));
+            }
+            for part in &parts {
+                match part {
+                    Part::Epithet(epithet) => variant.push(String::from(*epithet)),
+                    Part::Rank(rank, _) if *with_ranks => variant.push(String::from(*rank)),
+                    Part::Authorship(word) if !options.strip_authorship => {
+                        variant.push(String::from(*word));
+                    }
+                    _ => {}
+                }
+            }
+            let variant = variant.join(" ");
+            if variant != name && !variants.contains(&variant) {
+                variants.push(variant);
+            }
+        }
+    }
+    variants
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_taxon_variants() {
+        let options = TaxonVariantOptions::default();
+        assert_eq!(
+            taxon_variants("Puffinus puffinus (Brünnich, 1764)", &options),
+            vec!["Puffinus puffinus"]
+        );
+        assert_eq!(
+            taxon_variants("Puffinus (Puffinus) puffinus", &options),
+            vec!["Puffinus puffinus"]
+        );
+        assert_eq!(
+            taxon_variants(
+                "Puffinus subgen. Puffinus puffinus Brünnich, 1764",
+                &options
+            ),
+            vec!["Puffinus (Puffinus) puffinus", "Puffinus puffinus"]
+        );
+        assert_eq!(
+            taxon_variants("Homo sapiens ssp. sapiens Linnaeus, 1758", &options),
+            vec!["Homo sapiens subsp. sapiens", "Homo sapiens sapiens"]
+        );
+        // authorships in the middle of infraspecific names and lowercase author particles
+        assert_eq!(
+            taxon_variants("Salix alba L. var. vitellina (L.) de Candolle", &options),
+            vec!["Salix alba var. vitellina", "Salix alba vitellina"]
+        );
+        // "f." without a following epithet is part of the authorship
+        assert_eq!(
+            taxon_variants("Carex digitata L. f.", &options),
+            vec!["Carex digitata"]
+        );
+        assert_eq!(
+            taxon_variants("Sula bassana", &options),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            taxon_variants("northern gannet", &options),
+            Vec::<String>::new()
+        );
+
+        let options = TaxonVariantOptions {
+            strip_authorship: false,
+            subgenus: SubgenusForm::Drop,
+            rank_markers: RankMarkerForm::Normalize,
+        };
+        assert_eq!(
+            taxon_variants(
+                "Puffinus (Puffinus) puffinus ssp. puffinus Brünnich",
+                &options
+            ),
+            vec!["Puffinus puffinus subsp. puffinus Brünnich"]
+        );
+    }
+}
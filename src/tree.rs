@@ -1,40 +1,135 @@
-use std::cmp::Ordering;
-use std::collections::{HashMap, HashSet};
+use std::borrow::Cow;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::fmt::{Display, Formatter};
 use std::hash::Hash;
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock};
 
+use anyhow::{anyhow, Context};
 use indicatif::{ProgressBar, ProgressStyle};
 use itertools::Itertools;
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 
+use crate::abbreviation::{abbreviate, AbbreviationOptions};
+use crate::fuzzy::{FuzzyIndex, FuzzyOptions, FuzzyPath};
+use crate::row_filter::DroppedRows;
+use crate::skip_gram::{LazySkipGrams, SkipGramOptions, SkipGramPolicy};
+use crate::taxonomy::{taxon_variants, TaxonVariantOptions};
 use crate::util::{
-    create_skip_grams, get_files, parse_files, CorpusFormat, Tokenizer, TokensAndOffsets,
+    create_skip_grams, get_files, parse_files, CorpusFormat, CorpusRow, Metadata, OffsetUnit,
+    Tokenizer, TokensAndOffsets,
 };
 
-#[derive(Debug, Serialize, Deserialize)] // FIXME
+/// How overlapping matches are resolved. All strategies return their results ordered by start
+/// position.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)] // FIXME
 pub enum ResultSelection {
     All,
     Last,
+    #[default]
     LastPreferFull,
+    /// Scans the text from left to right and selects the longest match at each position, then
+    /// continues after its end. The results never overlap.
+    LeftmostLongest,
+    /// Selects matches greedily by the best match type among their matches (`Full`, `Variant`,
+    /// `Abbreviated`, `SkipGram`, `Fuzzy`, then `Gapped`, see `MatchType::get_value`), then by
+    /// length (longest first) and then by start position (leftmost first), skipping any match that
+    /// overlaps an already selected one. The results never overlap.
+    HighestPriorityLongest,
+    /// Keeps all matches, ordered by start position and then by length (longest first), and marks
+    /// those that are fully covered by another match as nested. Partially overlapping matches are
+    /// not nested in each other.
+    AllMarkNested,
+}
+
+/// A span of the searched text together with all of its matches.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SearchResult {
+    /// The (normalized) tokens of the span, joined by whitespace.
+    pub string: String,
+    pub matches: Vec<SearchMatch>,
+    pub begin: usize,
+    pub end: usize,
+    /// Whether the span is fully covered by another result. Only set by
+    /// `ResultSelection::AllMarkNested`.
+    pub nested: bool,
+}
+
+/// The options of a single search.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct SearchOptions {
+    /// The maximum number of tokens of a match. Defaults to the depth of the tree.
+    pub max_len: Option<usize>,
+    /// Defaults to `ResultSelection::LastPreferFull`.
+    pub result_selection: Option<ResultSelection>,
+    pub corpus_filter: Option<CorpusFilter>,
+    /// If true, `Abbreviated` matches are only kept if the text contains a `Full` match of the
+    /// same entry before them, see `SearchMatch::licensed_by`.
+    pub license_abbreviations: bool,
+    /// If given, matches with a lower `SearchMatch::score` are dropped.
+    pub min_score: Option<f64>,
+    /// If given, `Full` entries are also matched with up to this many additional tokens in the
+    /// text between their tokens, see `MatchType::Gapped`. Clamped to `HashMapSearchTree::max_gap`.
+    pub max_gap: Option<usize>,
+    /// The unit of the offsets of the results, characters by default.
+    pub offset_unit: OffsetUnit,
+}
+
+/// Restricts search results to matches from certain corpora, identified by their names in the
+/// configuration. Matches keep only the provenances of accepted corpora, and matches without
+/// provenance are only accepted if no include list is given.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct CorpusFilter {
+    /// If given, only matches from these corpora are accepted.
+    pub include: Option<HashSet<String>>,
+    /// Matches from these corpora are never accepted.
+    pub exclude: Option<HashSet<String>>,
+}
+
+impl CorpusFilter {
+    #[must_use]
+    pub fn accepts(&self, corpus: Option<&str>) -> bool {
+        let included = match (&self.include, corpus) {
+            (None, _) => true,
+            (Some(include), Some(corpus)) => include.contains(corpus),
+            (Some(_), None) => false,
+        };
+        let excluded = match (&self.exclude, corpus) {
+            (Some(exclude), Some(corpus)) => exclude.contains(corpus),
+            _ => false,
+        };
+        included && !excluded
+    }
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub enum MatchType {
     None,
     Full,
+    /// A canonical variant of a `Full` entry, see `TaxonVariantOptions`.
+    Variant,
     Abbreviated,
     SkipGram,
+    /// A `Full` entry matched with character-level edits, see `SearchMatch::distance`.
+    Fuzzy,
+    /// A `Full` entry matched with additional tokens in the text between its tokens, see
+    /// `SearchMatch::gaps`.
+    Gapped,
 }
 
 impl MatchType {
+    /// The priority of the match type, lower is better. New match types must also be added to the
+    /// order documented on `ResultSelection::HighestPriorityLongest`.
     const fn get_value(&self) -> i32 {
         match self {
             Self::None => -1,
             Self::Full => 0,
-            Self::Abbreviated => 1,
-            Self::SkipGram => 2,
+            Self::Variant => 1,
+            Self::Abbreviated => 2,
+            Self::SkipGram => 3,
+            Self::Fuzzy => 4,
+            Self::Gapped => 5,
         }
     }
 }
@@ -60,35 +155,138 @@ impl Display for MatchType {
             Self::Full => {
                 write!(f, "Full")
             }
+            Self::Variant => {
+                write!(f, "Variant")
+            }
             Self::Abbreviated => {
                 write!(f, "Abbreviated")
             }
             Self::SkipGram => {
                 write!(f, "SkipGram")
             }
+            Self::Fuzzy => {
+                write!(f, "Fuzzy")
+            }
+            Self::Gapped => {
+                write!(f, "Gapped")
+            }
         }
     }
 }
 
-#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+/// The weight of each match type in `SearchMatch::score`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ScoreWeights {
+    pub full: f64,
+    pub variant: f64,
+    pub abbreviated: f64,
+    pub skip_gram: f64,
+    pub fuzzy: f64,
+    pub gapped: f64,
+}
+
+impl Default for ScoreWeights {
+    fn default() -> Self {
+        ScoreWeights {
+            full: 1.0,
+            variant: 0.9,
+            abbreviated: 0.8,
+            skip_gram: 0.6,
+            fuzzy: 0.7,
+            gapped: 0.5,
+        }
+    }
+}
+
+impl ScoreWeights {
+    #[must_use]
+    pub fn weight(&self, match_type: &MatchType) -> f64 {
+        match match_type {
+            MatchType::None => 0.0,
+            MatchType::Full => self.full,
+            MatchType::Variant => self.variant,
+            MatchType::Abbreviated => self.abbreviated,
+            MatchType::SkipGram => self.skip_gram,
+            MatchType::Fuzzy => self.fuzzy,
+            MatchType::Gapped => self.gapped,
+        }
+    }
+
+    /// Scores a match of a key with `length` tokens, for which `skipped` tokens of the entry or of
+    /// the text were skipped and which shares its key with `labels` distinct labels. The score is the product of
+    /// the weight of the match type, `length / (length + 1)`, `1 / (skipped + 1)` and
+    /// `1 / labels`, so it lies between 0 and the largest weight.
+    #[must_use]
+    pub fn score(
+        &self,
+        match_type: &MatchType,
+        length: usize,
+        skipped: usize,
+        labels: usize,
+    ) -> f64 {
+        let length = length as f64;
+        self.weight(match_type) * length
+            / (length + 1.0)
+            / (skipped as f64 + 1.0)
+            / labels.max(1) as f64
+    }
+}
+
+/// Where an entry of the tree was read from.
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Ord, PartialOrd, Serialize, Deserialize)]
+pub struct Provenance {
+    /// The name of the corpus, i.e. its key in the configuration.
+    pub corpus: Arc<String>,
+    /// The file the entry was read from.
+    pub source: Arc<String>,
+    /// The (1-based) line number of the entry in its source file.
+    pub row: u64,
+    /// Additional columns of the entry, see `CorpusFormat::metadata_columns`.
+    pub metadata: Metadata,
+    /// Whether the entry is a synonym of the primary name of its row, see
+    /// `CorpusFormat::alternate_name_columns`.
+    pub synonym: bool,
+}
+
+impl Display for Provenance {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({}:{})", self.corpus, self.source, self.row)
+    }
+}
+
+/// An entry of the tree, stored at the node of its (tokenized) key. The same entry loaded from
+/// several rows, files or corpora is stored once, with all of their provenances.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct Match {
     pub match_type: MatchType,
     pub match_string: Arc<String>,
     pub match_label: Arc<String>,
+    /// The positions of the entry tokens that were skipped to produce the key of a
+    /// `MatchType::SkipGram` match, in ascending order.
+    pub skipped: Box<[u32]>,
+    /// Where the entry was loaded from, in loading order. Shared by all matches generated from
+    /// the same row, and empty for entries that were not loaded from a corpus file.
+    pub provenances: Vec<Arc<Provenance>>,
 }
 
-impl Ord for Match {
-    fn cmp(&self, other: &Self) -> Ordering {
-        self.match_type
-            .cmp(&other.match_type)
-            .then(self.match_string.cmp(&other.match_string))
-            .then(self.match_label.cmp(&other.match_label))
+impl Match {
+    /// Identifies the entry within its node, which holds at most one match per key.
+    fn key(&self) -> (&MatchType, &Arc<String>, &Arc<String>, &[u32]) {
+        (
+            &self.match_type,
+            &self.match_string,
+            &self.match_label,
+            &self.skipped,
+        )
     }
-}
 
-impl PartialOrd<Self> for Match {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.cmp(other))
+    /// The distinct names of the corpora this match was loaded from, in loading order.
+    pub fn corpora(&self) -> impl Iterator<Item = &str> {
+        self.provenances
+            .iter()
+            .map(|provenance| provenance.corpus.as_str())
+            .unique()
     }
 }
 
@@ -102,19 +300,258 @@ impl Display for Match {
     }
 }
 
+/// A match as returned by `search`: an entry of the tree together with how it was found in the
+/// text.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SearchMatch {
+    /// The type of the match, which differs from the type of the entry for matches found at
+    /// query time, i.e. `MatchType::Fuzzy`.
+    pub match_type: MatchType,
+    pub match_string: Arc<String>,
+    pub match_label: Arc<String>,
+    /// The provenances of the entry from the corpora that allow this match, see
+    /// `Match::provenances`.
+    pub provenances: Vec<Arc<Provenance>>,
+    /// The total edit distance of a `MatchType::Fuzzy` match, 0 otherwise.
+    pub distance: u32,
+    /// The positions of the entry tokens that were skipped by a `MatchType::SkipGram` match, see
+    /// `Match::skipped`.
+    pub skipped: Vec<u32>,
+    /// The skipped tokens, see `skipped`.
+    pub skipped_tokens: Vec<String>,
+    /// The `(begin, end)` offsets of the tokens in the text that a `MatchType::Gapped` match
+    /// skipped.
+    pub gaps: Vec<(usize, usize)>,
+    /// The `(begin, end)` offsets of the first `Full` match of the same entry that licensed this
+    /// `Abbreviated` match. Only set if `SearchOptions::license_abbreviations` is enabled.
+    pub licensed_by: Option<(usize, usize)>,
+    /// The confidence in this match as computed by `ScoreWeights::score`.
+    pub score: f64,
+}
+
+impl SearchMatch {
+    /// The distinct names of the corpora this match was loaded from, in loading order.
+    pub fn corpora(&self) -> impl Iterator<Item = &str> {
+        self.provenances
+            .iter()
+            .map(|provenance| provenance.corpus.as_str())
+            .unique()
+    }
+
+    /// Whether this match was only loaded from alternate names marked as synonyms.
+    #[must_use]
+    pub fn is_synonym(&self) -> bool {
+        !self.provenances.is_empty() && self.provenances.iter().all(|provenance| provenance.synonym)
+    }
+
+    /// Orders matches by their entry, i.e. by type, string, label and provenances.
+    #[must_use]
+    pub fn cmp_entry(&self, other: &Self) -> Ordering {
+        (
+            &self.match_type,
+            &self.match_string,
+            &self.match_label,
+            &self.provenances,
+        )
+            .cmp(&(
+                &other.match_type,
+                &other.match_string,
+                &other.match_label,
+                &other.provenances,
+            ))
+    }
+}
+
+impl Display for SearchMatch {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} Match: {} -> {}",
+            self.match_type, self.match_string, self.match_label
+        )
+    }
+}
+
+/// The id of the root node of the search tree.
+pub(crate) const ROOT: u32 = 0;
+/// Marks the absence of a node in the automaton links.
+const NO_NODE: u32 = u32::MAX;
+/// The default of `HashMapSearchTree::max_gap`. Gapped walks grow combinatorially with the gap.
+pub const DEFAULT_MAX_GAP: usize = 3;
+
+/// A node of the search tree. Each node represents the sequence of tokens on the path from the
+/// root to the node and holds the matches for entries that consist of exactly this sequence.
 #[derive(Debug, Default)]
+pub(crate) struct Node {
+    pub(crate) parent: u32,
+    pub(crate) token: u32,
+    pub(crate) depth: u32,
+    /// The matches of this node, kept sorted and free of duplicates.
+    pub(crate) matches: Vec<Match>,
+}
+
+/// The children of all nodes in compressed form: the children of node `n` are
+/// `children[offsets[n]..offsets[n + 1]]`. Only built for query-time skip-grams.
+#[derive(Debug)]
+struct Children {
+    offsets: Vec<u32>,
+    children: Vec<u32>,
+}
+
+impl Children {
+    fn get(&self, node: u32) -> &[u32] {
+        let node = node as usize;
+        &self.children[self.offsets[node] as usize..self.offsets[node + 1] as usize]
+    }
+}
+
+/// The Aho-Corasick links of the search tree, which allow finding all matches in a single pass.
+#[derive(Debug)]
+struct Links {
+    /// The node of the longest proper suffix of each node that is also in the tree.
+    fail: Vec<u32>,
+    /// The next node with matches on the failure chain of each node, or `NO_NODE`.
+    output: Vec<u32>,
+}
+
+/// A token trie over interned token ids, searched as an Aho-Corasick automaton.
+#[derive(Debug)]
 pub struct HashMapSearchTree {
-    pub search_map: HashMap<Vec<String>, HashSet<Match>>,
-    tokenizer: Tokenizer,
-    tree_depth: usize,
+    /// Maps each token to its interned id.
+    pub(crate) vocabulary: HashMap<String, u32>,
+    /// All nodes of the tree, `nodes[ROOT]` being the root. Children always have a larger id than
+    /// their parents.
+    pub(crate) nodes: Vec<Node>,
+    /// Maps a `(parent, token)` pair to the child node.
+    pub(crate) edges: HashMap<(u32, u32), u32>,
+    /// Lazily (re-)built after the tree has been modified.
+    links: OnceLock<Links>,
+    /// Lazily (re-)built after the tree has been modified, if needed.
+    children: OnceLock<Children>,
+    /// The names of all corpora that contributed matches.
+    pub(crate) corpora: BTreeSet<String>,
+    pub(crate) fuzzy: FuzzyIndex,
+    pub(crate) lazy_skip_grams: LazySkipGrams,
+    /// The skip-gram policies of all corpora that do not use the default policy.
+    pub(crate) skip_gram_policies: HashMap<String, SkipGramPolicy>,
+    /// The abbreviation options of all corpora that do not use the default options.
+    abbreviation_options: HashMap<String, AbbreviationOptions>,
+    /// The corpora for which canonical variants of scientific names are generated.
+    taxon_variant_options: HashMap<String, TaxonVariantOptions>,
+    pub(crate) tokenizer: Tokenizer,
+    pub(crate) score_weights: ScoreWeights,
+    /// The largest `SearchOptions::max_gap` a search may use.
+    pub(crate) max_gap: usize,
+    pub(crate) tree_depth: usize,
+}
+
+impl Default for HashMapSearchTree {
+    fn default() -> Self {
+        HashMapSearchTree {
+            vocabulary: HashMap::new(),
+            nodes: vec![Node::default()],
+            edges: HashMap::new(),
+            links: OnceLock::new(),
+            children: OnceLock::new(),
+            corpora: BTreeSet::new(),
+            fuzzy: FuzzyIndex::default(),
+            lazy_skip_grams: LazySkipGrams::default(),
+            skip_gram_policies: HashMap::new(),
+            abbreviation_options: HashMap::new(),
+            taxon_variant_options: HashMap::new(),
+            tokenizer: Tokenizer::default(),
+            score_weights: ScoreWeights::default(),
+            max_gap: DEFAULT_MAX_GAP,
+            tree_depth: 0,
+        }
+    }
+}
+
+impl HashMapSearchTree {
+    /// Creates an empty tree that tokenizes both its entries and its queries with the given
+    /// tokenizer.
+    #[must_use]
+    pub fn with_tokenizer(tokenizer: Tokenizer) -> Self {
+        HashMapSearchTree {
+            tokenizer,
+            ..Default::default()
+        }
+    }
+
+    /// Reassembles a tree from its interned vocabulary and its nodes, given in id order as
+    /// `(parent, token, matches)`.
+    pub(crate) fn from_parts(
+        vocabulary: HashMap<String, u32>,
+        parts: Vec<(u32, u32, Vec<Match>)>,
+        tokenizer: Tokenizer,
+    ) -> anyhow::Result<Self> {
+        let mut tree = HashMapSearchTree {
+            vocabulary,
+            tokenizer,
+            ..Default::default()
+        };
+        tree.nodes.reserve(parts.len());
+        for (idx, (parent, token, matches)) in parts.into_iter().enumerate().skip(1) {
+            if parent as usize >= idx {
+                return Err(anyhow!("Node {idx} has an invalid parent {parent}"));
+            }
+            let child = tree.get_or_insert_child(parent, token);
+            tree.tree_depth = tree
+                .tree_depth
+                .max(tree.nodes[child as usize].depth as usize);
+            tree.corpora
+                .extend(matches.iter().flat_map(Match::corpora).map(String::from));
+            tree.nodes[child as usize].matches = matches;
+        }
+        Ok(tree)
+    }
+}
+
+/// Statistics on loading entries into the tree.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct LoadSummary {
+    pub entries: usize,
+    pub skip_grams: usize,
+    /// The skip-gram variants that were not generated due to `SkipGramPolicy::max_variants`.
+    pub capped_skip_grams: usize,
+    /// The skip-grams that were dropped due to `SkipGramPolicy::drop_collisions`.
+    pub colliding_skip_grams: usize,
+    /// The canonical variants of scientific names, see `TaxonVariantOptions`.
+    pub taxon_variants: usize,
+    /// The number of rows dropped by each of the `row_filters` of the corpus format.
+    pub dropped_rows: DroppedRows,
+}
+
+impl Display for LoadSummary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} entries, {} taxon variants, {} skip-grams ({} capped, {} dropped as colliding)",
+            self.entries,
+            self.taxon_variants,
+            self.skip_grams,
+            self.capped_skip_grams,
+            self.colliding_skip_grams
+        )?;
+        for (filter, count) in &self.dropped_rows {
+            write!(f, ", {count} rows dropped by {filter}")?;
+        }
+        Ok(())
+    }
 }
 
-type EntryType = (Vec<String>, Arc<String>, Arc<String>);
+type EntryType = (
+    Vec<String>,
+    Arc<String>,
+    Arc<String>,
+    Option<Arc<Provenance>>,
+);
 
 impl HashMapSearchTree {
     #[allow(clippy::too_many_arguments)]
     pub fn load_file(
         &mut self,
+        corpus: &str,
         root_path: &str,
         generate_skip_grams: bool,
         skip_gram_min_length: i32,
@@ -124,21 +561,36 @@ impl HashMapSearchTree {
         abbrv_max_index: i32,
         abbrv_min_suffix_length: i32,
         format: &Option<CorpusFormat>,
-    ) {
+    ) -> anyhow::Result<LoadSummary> {
         let files: Vec<String> = get_files(root_path);
-        println!("Found {} files to read", files.len());
+        log::info!("Found {} files to read", files.len());
 
         let pb = ProgressBar::new(files.len() as u64);
         pb.set_style(
             ProgressStyle::with_template("Loading Input Files {bar:40} {pos}/{len} {msg}").unwrap(),
         );
-        let lines: Vec<(String, String)> =
+        let (rows, dropped_rows): (Vec<CorpusRow>, DroppedRows) =
             parse_files(&files, Option::from(&pb), format, filter_list)
-                .expect("Failed to parse an input file");
+                .context("Failed to parse an input file")?;
         pb.finish_with_message("Done");
 
-        self.load(
-            lines,
+        let corpus = Arc::new(String::from(corpus));
+        let entries = rows
+            .into_iter()
+            .map(|row| {
+                let provenance = Provenance {
+                    corpus: corpus.clone(),
+                    source: row.source,
+                    row: row.row,
+                    metadata: row.metadata,
+                    synonym: row.synonym,
+                };
+                (row.search_term, row.label, Some(Arc::new(provenance)))
+            })
+            .collect();
+
+        let summary = self.load_with_provenance(
+            entries,
             generate_skip_grams,
             skip_gram_min_length,
             skip_gram_max_skips,
@@ -146,8 +598,13 @@ impl HashMapSearchTree {
             abbrv_max_index,
             abbrv_min_suffix_length,
         );
+        Ok(LoadSummary {
+            dropped_rows,
+            ..summary
+        })
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn load(
         &mut self,
         entries: Vec<(String, String)>,
@@ -157,26 +614,78 @@ impl HashMapSearchTree {
         generate_abbrv: bool,
         abbrv_max_index: i32,
         abbrv_min_suffix_length: i32,
-    ) {
+    ) -> LoadSummary {
+        let entries = entries
+            .into_iter()
+            .map(|(search_term, label)| (search_term, label, None))
+            .collect();
+        self.load_with_provenance(
+            entries,
+            generate_skip_grams,
+            skip_gram_min_length,
+            skip_gram_max_skips,
+            generate_abbrv,
+            abbrv_max_index,
+            abbrv_min_suffix_length,
+        )
+    }
+
+    /// Loads the given entries, generating skip-grams according to the `SkipGramPolicy` of
+    /// their corpus, see `set_skip_gram_policy`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn load_with_provenance(
+        &mut self,
+        entries: Vec<(String, String, Option<Arc<Provenance>>)>,
+        generate_skip_grams: bool,
+        skip_gram_min_length: i32,
+        skip_gram_max_skips: i32,
+        generate_abbrv: bool,
+        abbrv_max_index: i32,
+        abbrv_min_suffix_length: i32,
+    ) -> LoadSummary {
+        for (_, _, provenance) in &entries {
+            if let Some(provenance) = provenance {
+                if !self.corpora.contains(provenance.corpus.as_str()) {
+                    self.corpora.insert((*provenance.corpus).clone());
+                }
+            }
+        }
+
         let search_terms: Vec<&str> = entries.iter().map(|line| line.0.as_str()).collect();
         let segmented: Vec<TokensAndOffsets> = self.tokenize_batch(search_terms.as_slice());
         let entries: Vec<EntryType> = segmented
             .into_iter()
             .zip(entries)
-            .map(|(segments, (search_term, label))| {
-                (segments.0, Arc::from(search_term), Arc::from(label))
+            .map(|(segments, (search_term, label, provenance))| {
+                (
+                    segments.0,
+                    Arc::from(search_term),
+                    Arc::from(label),
+                    provenance,
+                )
             })
             .collect();
 
         self.load_entries(&entries);
 
+        let mut summary = LoadSummary {
+            entries: entries.len(),
+            ..Default::default()
+        };
+        if !self.taxon_variant_options.is_empty() {
+            summary.taxon_variants = self.generate_taxon_variants(&entries);
+        }
         if generate_skip_grams {
-            self.generate_skip_grams(&entries, skip_gram_min_length, skip_gram_max_skips);
+            (summary.skip_grams, summary.capped_skip_grams) =
+                self.generate_skip_grams(&entries, skip_gram_min_length, skip_gram_max_skips);
         }
 
         if generate_abbrv {
             self.generate_abbreviations(&entries, abbrv_max_index, abbrv_min_suffix_length);
         }
+
+        self.links();
+        summary
     }
 
     pub(crate) fn load_entries(&mut self, entries: &Vec<EntryType>) {
@@ -185,12 +694,13 @@ impl HashMapSearchTree {
             ProgressStyle::with_template("Loading Entries {bar:40} {pos}/{len} {msg}").unwrap(),
         );
 
-        for (segments, search_term, label) in entries {
+        for (segments, search_term, label, provenance) in entries {
             self.insert(
                 segments.clone(),
                 search_term.clone(),
                 label.clone(),
                 MatchType::Full,
+                provenance.clone(),
             );
             pb.inc(1);
         }
@@ -203,30 +713,223 @@ impl HashMapSearchTree {
         match_string: Arc<String>,
         match_label: Arc<String>,
         match_type: MatchType,
+        provenance: Option<Arc<Provenance>>,
     ) {
+        self.insert_match(
+            segments,
+            Match {
+                match_type,
+                match_string,
+                match_label,
+                skipped: Box::default(),
+                provenances: provenance.into_iter().collect(),
+            },
+        );
+    }
+
+    fn insert_match(&mut self, segments: Vec<String>, mtch: Match) {
+        // an empty key could never be found
+        if segments.is_empty() {
+            return;
+        }
         if segments.len() > self.tree_depth {
             self.tree_depth = segments.len();
         }
 
-        match self.search_map.get_mut(&segments) {
-            Some(search_result) => {
-                search_result.insert(Match {
-                    match_type,
-                    match_string,
-                    match_label,
+        let mut node = ROOT;
+        for segment in segments {
+            let token = self.intern(segment);
+            node = self.get_or_insert_child(node, token);
+        }
+
+        let matches = &mut self.nodes[node as usize].matches;
+        match matches.binary_search_by(|other| other.key().cmp(&mtch.key())) {
+            // the same entry from another row, file or corpus
+            Ok(idx) => {
+                let provenances = &mut matches[idx].provenances;
+                for provenance in mtch.provenances {
+                    if !provenances.contains(&provenance) {
+                        provenances.push(provenance);
+                    }
+                }
+            }
+            Err(idx) => matches.insert(idx, mtch),
+        }
+    }
+
+    fn intern(&mut self, token: String) -> u32 {
+        let next_id = self.vocabulary.len() as u32;
+        *self.vocabulary.entry(token).or_insert(next_id)
+    }
+
+    pub(crate) fn get_or_insert_child(&mut self, parent: u32, token: u32) -> u32 {
+        if let Some(child) = self.edges.get(&(parent, token)) {
+            return *child;
+        }
+        let child = self.nodes.len() as u32;
+        self.nodes.push(Node {
+            parent,
+            token,
+            depth: self.nodes[parent as usize].depth + 1,
+            matches: Vec::new(),
+        });
+        self.edges.insert((parent, token), child);
+        self.links.take();
+        self.children.take();
+        child
+    }
+
+    /// Enables skip-grams of the `Full` entries of the given corpus at query time. The corpus
+    /// should be loaded without generating skip-grams.
+    pub fn enable_lazy_skip_grams(&mut self, corpus: &str, options: SkipGramOptions) {
+        self.lazy_skip_grams.add_corpus(corpus, options);
+    }
+
+    /// Sets the skip-gram policy of the given corpus, which applies to skip-grams generated while
+    /// loading the corpus and to skip-grams found at query time.
+    pub fn set_skip_gram_policy(&mut self, corpus: &str, policy: SkipGramPolicy) {
+        if policy == SkipGramPolicy::default() {
+            self.skip_gram_policies.remove(corpus);
+        } else {
+            self.skip_gram_policies.insert(String::from(corpus), policy);
+        }
+    }
+
+    fn skip_gram_policy(&self, corpus: Option<&str>) -> SkipGramPolicy {
+        corpus
+            .and_then(|corpus| self.skip_gram_policies.get(corpus))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Sets the options for generating abbreviations of the given corpus, which must be set before
+    /// loading the corpus.
+    pub fn set_abbreviation_options(&mut self, corpus: &str, options: AbbreviationOptions) {
+        if options == AbbreviationOptions::default() {
+            self.abbreviation_options.remove(corpus);
+        } else {
+            self.abbreviation_options
+                .insert(String::from(corpus), options);
+        }
+    }
+
+    /// Enables generating canonical variants of the scientific names of the given corpus, which
+    /// must be set before loading the corpus.
+    pub fn enable_taxon_variants(&mut self, corpus: &str, options: TaxonVariantOptions) {
+        self.taxon_variant_options
+            .insert(String::from(corpus), options);
+    }
+
+    /// Removes all `SkipGram` matches whose key is also the key of a `Full` match with a
+    /// different label, for the corpora whose policy has `drop_collisions` set. Should be called
+    /// after all corpora are loaded. Returns the number of dropped matches per corpus.
+    pub fn drop_colliding_skip_grams(&mut self) -> HashMap<String, usize> {
+        let mut dropped: HashMap<String, usize> = HashMap::new();
+        if !self
+            .skip_gram_policies
+            .values()
+            .any(|policy| policy.drop_collisions)
+        {
+            return dropped;
+        }
+        let policies = &self.skip_gram_policies;
+        for node in &mut self.nodes {
+            let labels: HashSet<Arc<String>> = node
+                .matches
+                .iter()
+                .filter(|mtch| mtch.match_type == MatchType::Full)
+                .map(|mtch| mtch.match_label.clone())
+                .collect();
+            if labels.is_empty() {
+                continue;
+            }
+            node.matches.retain_mut(|mtch| {
+                if mtch.match_type != MatchType::SkipGram
+                    || labels.contains(&mtch.match_label)
+                    || mtch.provenances.is_empty()
+                {
+                    return true;
+                }
+                // only the provenances of the corpora that drop collisions are removed
+                mtch.provenances.retain(|provenance| {
+                    let collides = policies
+                        .get(provenance.corpus.as_str())
+                        .is_some_and(|policy| policy.drop_collisions);
+                    if collides {
+                        *dropped.entry(provenance.corpus.to_string()).or_default() += 1;
+                    }
+                    !collides
                 });
+                !mtch.provenances.is_empty()
+            });
+        }
+        dropped
+    }
+
+    /// Enables fuzzy matching for the `Full` entries of the given corpus, which must already be
+    /// loaded.
+    pub fn enable_fuzzy(&mut self, corpus: &str, options: FuzzyOptions) {
+        self.fuzzy.add_corpus(corpus, options);
+
+        let mut token_ids: HashSet<u32> = HashSet::new();
+        for node in &self.nodes {
+            if !node.matches.iter().any(|mtch| {
+                mtch.match_type == MatchType::Full && mtch.corpora().any(|c| c == corpus)
+            }) {
+                continue;
             }
-            None => {
-                self.search_map.insert(
-                    segments,
-                    HashSet::from([Match {
-                        match_type,
-                        match_string,
-                        match_label,
-                    }]),
-                );
+            let mut node = node;
+            while node.depth > 0 {
+                token_ids.insert(node.token);
+                node = &self.nodes[node.parent as usize];
             }
         }
+
+        let tokens: HashMap<u32, &String> = self
+            .vocabulary
+            .iter()
+            .filter(|(_, id)| token_ids.contains(id))
+            .map(|(token, id)| (*id, token))
+            .collect();
+        for (id, token) in tokens {
+            self.fuzzy.add_token(id, token, &options);
+        }
+    }
+
+    /// Returns the matches stored for exactly the given (tokenized) search term.
+    #[must_use]
+    pub fn get(&self, segments: &[String]) -> Option<&[Match]> {
+        let mut node = ROOT;
+        for segment in segments {
+            let token = self.vocabulary.get(segment)?;
+            node = *self.edges.get(&(node, *token))?;
+        }
+        let matches = &self.nodes[node as usize].matches;
+        if matches.is_empty() {
+            None
+        } else {
+            Some(matches)
+        }
+    }
+
+    /// The names of all corpora that were loaded into the tree.
+    #[must_use]
+    pub fn corpora(&self) -> &BTreeSet<String> {
+        &self.corpora
+    }
+
+    /// The number of distinct keys in the tree.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.nodes
+            .iter()
+            .filter(|node| !node.matches.is_empty())
+            .count()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
     }
 
     pub(crate) fn generate_skip_grams(
@@ -234,10 +937,10 @@ impl HashMapSearchTree {
         lines: &[EntryType],
         min_length: i32,
         max_skips: i32,
-    ) {
+    ) -> (usize, usize) {
         let filtered = lines
             .iter()
-            .filter(|(segments, _, _)| segments.len() > min_length as usize)
+            .filter(|(segments, _, _, _)| segments.len() > min_length as usize)
             .collect::<Vec<_>>();
 
         let pb = ProgressBar::new(filtered.len() as u64);
@@ -246,23 +949,80 @@ impl HashMapSearchTree {
                 .unwrap(),
         );
 
-        let mut counter: i64 = 0;
-        for (segments, search_term, label) in filtered {
-            let mut deletes = create_skip_grams(vec![segments.clone()], max_skips, min_length);
+        let (mut counter, mut capped) = (0, 0);
+        for (segments, search_term, label, provenance) in filtered {
+            let policy = self.skip_gram_policy(provenance.as_ref().map(|p| p.corpus.as_str()));
+            let mut deletes =
+                create_skip_grams(vec![(segments.clone(), Vec::new())], max_skips, min_length);
+            deletes.retain(|(_, skipped)| policy.allows(segments.len(), skipped));
+            if let Some(max_variants) = policy.max_variants {
+                // rank the variants by the number of skipped tokens and then by their positions,
+                // as in `SkipGramPolicy::within_max_variants`
+                deletes.sort_by(|(_, a), (_, b)| a.len().cmp(&b.len()).then_with(|| a.cmp(b)));
+                deletes.dedup_by(|(_, b), (_, a)| a == b);
+                capped += deletes.len().saturating_sub(max_variants);
+                deletes.truncate(max_variants);
+            }
+            // keep the variant with the leftmost skipped positions for each key
             deletes.sort();
-            deletes.dedup();
-            for skip_gram in deletes {
-                self.insert(
+            deletes.dedup_by(|(b, _), (a, _)| a == b);
+            for (skip_gram, skipped) in deletes {
+                self.insert_match(
                     skip_gram,
-                    search_term.clone(),
-                    label.clone(),
-                    MatchType::SkipGram,
+                    Match {
+                        match_type: MatchType::SkipGram,
+                        match_string: search_term.clone(),
+                        match_label: label.clone(),
+                        skipped: skipped.into_iter().map(|idx| idx as u32).collect(),
+                        provenances: provenance.iter().cloned().collect(),
+                    },
                 );
                 counter += 1;
             }
             pb.inc(1);
         }
         pb.finish_with_message(format!("Generated {counter} skip-grams"));
+        (counter, capped)
+    }
+
+    pub(crate) fn generate_taxon_variants(&mut self, lines: &[EntryType]) -> usize {
+        let variants: Vec<(String, &EntryType)> = lines
+            .iter()
+            .filter_map(|line| {
+                let corpus = line.3.as_ref()?.corpus.as_str();
+                let options = self.taxon_variant_options.get(corpus)?;
+                Some(
+                    taxon_variants(&line.1, options)
+                        .into_iter()
+                        .map(move |variant| (variant, line)),
+                )
+            })
+            .flatten()
+            .collect();
+        let search_terms: Vec<&str> = variants
+            .iter()
+            .map(|(variant, _)| variant.as_str())
+            .collect();
+        let segmented: Vec<TokensAndOffsets> = self.tokenize_batch(search_terms.as_slice());
+
+        let mut counter = 0;
+        for ((segments, _), (_, (entry_segments, search_term, label, provenance))) in
+            segmented.into_iter().zip(variants)
+        {
+            // variants that only differ in punctuation may tokenize like the entry itself
+            if segments == *entry_segments {
+                continue;
+            }
+            self.insert(
+                segments,
+                search_term.clone(),
+                label.clone(),
+                MatchType::Variant,
+                provenance.clone(),
+            );
+            counter += 1;
+        }
+        counter
     }
 
     pub(crate) fn generate_abbreviations(
@@ -273,7 +1033,7 @@ impl HashMapSearchTree {
     ) {
         let filtered = lines
             .iter()
-            .filter(|(segments, _, _)| segments.len() > 1)
+            .filter(|(segments, _, _, _)| segments.len() > 1)
             .collect::<Vec<_>>();
 
         let pb = ProgressBar::new(filtered.len() as u64);
@@ -283,41 +1043,32 @@ impl HashMapSearchTree {
         );
 
         let mut counter: i64 = 0;
-        let mut abbrv: Vec<String> = Vec::new();
-        for (segments, search_term, label) in filtered {
-            // set the maximum abbreviated segment index to the last segment
-            // UNLESS the user has specified a maximum index (abbrv_max_index > 0)
-            let max_index = segments.len() - 1;
-            let max_index = if abbrv_max_index < 0 || abbrv_max_index > (max_index as i32) {
-                max_index
-            } else {
-                abbrv_max_index as usize
-            };
-            for i in 0..=max_index {
-                // check if the remaining segments after the abbreviated segment are long enough
-                // i.e., "Thing A" -> "T A"
-                let suffix_length: usize = segments[(i + 1)..].iter().map(String::len).sum();
-                if abbrv_min_suffix_length > 0 && suffix_length < (abbrv_min_suffix_length as usize)
-                {
-                    continue;
-                }
-
-                abbrv.clear();
-                let target_segment = String::from(&segments[i]);
-                let abbreviated_segment = target_segment.chars().next().unwrap().to_string();
-
-                // if we are not abbreviating the first segment, add the prefix segments in any case
-                if i > 0 {
-                    abbrv.extend_from_slice(&segments[0..i]);
-                }
-                abbrv.push(abbreviated_segment);
-                abbrv.extend_from_slice(&segments[(i + 1)..]);
-
+        for (segments, search_term, label, provenance) in filtered {
+            let options = provenance
+                .as_ref()
+                .and_then(|provenance| self.abbreviation_options.get(provenance.corpus.as_str()))
+                .cloned()
+                .unwrap_or_default();
+            let variants = abbreviate(
+                segments,
+                abbrv_max_index,
+                abbrv_min_suffix_length,
+                &options,
+                |prefix| {
+                    if options.period {
+                        self.tokenize(prefix).0
+                    } else {
+                        vec![String::from(prefix)]
+                    }
+                },
+            );
+            for abbrv in variants {
                 self.insert(
-                    abbrv.clone(),
+                    abbrv,
                     search_term.clone(),
                     label.clone(),
                     MatchType::Abbreviated,
+                    provenance.clone(),
                 );
                 counter += 1;
             }
@@ -335,131 +1086,857 @@ impl HashMapSearchTree {
         self.tokenizer.encode_batch(input)
     }
 
-    pub fn search<'a>(
-        &'a self,
-        text: &'a str,
-        max_len: Option<usize>,
-        result_selection: Option<&ResultSelection>,
-    ) -> Vec<(String, Vec<Match>, usize, usize)> {
-        let result_selection = result_selection.unwrap_or(&ResultSelection::LastPreferFull);
-        let max_len = max_len.unwrap_or(self.tree_depth);
-
-        let (mut slices, mut offsets) = self.tokenize(text);
-
-        // Pad the slices and their offsets to include the last words
-        slices.extend(vec![String::new(); max_len]);
-        offsets.extend(vec![(0, 0); max_len]);
-        let (slices, offsets) = (slices, offsets);
-
-        let mut results = slices
-            .par_windows(max_len)
-            .map(|slice| self.traverse(slice))
-            .zip(offsets.par_windows(max_len))
-            .filter_map(|(result, offsets)| result.map_or(None, |result| Some((result, offsets))))
-            .filter_map(|(result, offsets)| {
-                if result.is_empty() {
-                    None
-                } else {
-                    Some((result, offsets))
-                }
-            })
-            .map(|(results, offsets)| {
-                let start = offsets[0].0;
-                match result_selection {
-                    ResultSelection::All => {
-                        let mut returns = Vec::new();
-                        for result in results {
-                            let end = offsets[result.search_terms.len() - 1].1;
-                            returns.push((
-                                result.get_search_term_string(),
-                                result.get_search_results(),
-                                start,
-                                end,
-                            ));
-                        }
-                        returns
-                    }
-                    ResultSelection::Last => {
-                        let result = results.last().unwrap();
-                        let end = offsets[result.search_terms.len() - 1].1;
-                        vec![(
-                            result.get_search_term_string(),
-                            result.get_search_results(),
-                            start,
-                            end,
-                        )]
-                    }
-                    ResultSelection::LastPreferFull => {
-                        let result = results.last().unwrap();
-                        let end = offsets[result.search_terms.len() - 1].1;
-                        if result
-                            .search_results
+    pub fn search(&self, text: &str, options: &SearchOptions) -> Vec<SearchResult> {
+        let result_selection = options.result_selection.clone().unwrap_or_default();
+        let max_len = options.max_len.unwrap_or(self.tree_depth);
+
+        let (slices, offsets) = self.tokenize(text);
+        let offsets = options.offset_unit.convert(text, offsets);
+        let mut candidates = self.traverse(
+            &slices,
+            &offsets,
+            max_len,
+            options.max_gap.unwrap_or(0).min(self.max_gap),
+            options.corpus_filter.as_ref(),
+        );
+        if options.license_abbreviations {
+            candidates =
+                license_abbreviations(candidates, &offsets, &self.score_weights, options.min_score);
+        }
+        candidates = self.annotate(candidates, options.min_score);
+
+        match result_selection {
+            ResultSelection::All | ResultSelection::Last | ResultSelection::LastPreferFull => {
+                let mut results = candidates
+                    .into_par_iter()
+                    .map(|(start_idx, results)| match result_selection {
+                        ResultSelection::All => results
                             .iter()
-                            .any(|mtch| mtch.match_type == MatchType::Full)
-                        {
-                            let mut mtches = Vec::new();
-                            mtches.extend(result.get_search_results().into_iter().filter_map(
-                                |mtch| {
-                                    if mtch.match_type == MatchType::Full {
-                                        Some(mtch)
-                                    } else {
-                                        None
-                                    }
-                                },
-                            ));
-                            return vec![(result.get_search_term_string(), mtches, start, end)];
+                            .map(|result| result.to_search_result(self, start_idx, &offsets))
+                            .collect(),
+                        ResultSelection::Last => {
+                            let result = results.last().unwrap();
+                            vec![result.to_search_result(self, start_idx, &offsets)]
                         }
-                        vec![(
-                            result.get_search_term_string(),
-                            result.get_search_results(),
-                            start,
-                            end,
-                        )]
-                    }
-                }
-            })
-            .flatten()
-            // .map(|(s, mtches, a, b)| (s, mtches.into_iter().sorted().collect::<Vec<&Match>>(), a, b))
-            .collect::<Vec<(String, Vec<Match>, usize, usize)>>();
-
-        // results.dedup_by(|b, a| b.2 <= a.3);
-        // TODO: This removes fully covered entities that end on the same character as their covering entities but not partial overlaps
-        results.dedup_by_key(|el| el.3);
+                        _ => {
+                            let result = results.last().unwrap();
+                            let mut search_result =
+                                result.to_search_result(self, start_idx, &offsets);
+                            if search_result
+                                .matches
+                                .iter()
+                                .any(|mtch| mtch.match_type == MatchType::Full)
+                            {
+                                search_result
+                                    .matches
+                                    .retain(|mtch| mtch.match_type == MatchType::Full);
+                            }
+                            vec![search_result]
+                        }
+                    })
+                    .flatten()
+                    .collect::<Vec<SearchResult>>();
 
-        results
-    }
+                // results.dedup_by(|b, a| b.2 <= a.3);
+                // TODO: This removes fully covered entities that end on the same character as their covering entities but not partial overlaps
+                results.dedup_by_key(|el| el.end);
 
-    pub(crate) fn traverse(&self, window: &[String]) -> Result<Vec<TraversalResult>, String> {
-        let mut results = Vec::new();
-        for i in 0..window.len() {
-            let search_terms = window[0..=i].to_vec();
-            if let Some(search_results) = self.search_map.get(&search_terms) {
-                results.push(TraversalResult {
-                    search_terms,
-                    search_results,
+                results
+            }
+            ResultSelection::LeftmostLongest => {
+                let mut results = Vec::new();
+                let mut next_idx = 0;
+                for (start_idx, group) in &candidates {
+                    if *start_idx < next_idx {
+                        continue;
+                    }
+                    let longest = group.last().unwrap();
+                    next_idx = start_idx + longest.search_terms.len();
+                    results.push(longest.to_search_result(self, *start_idx, &offsets));
+                }
+                results
+            }
+            ResultSelection::HighestPriorityLongest => {
+                let mut spans: Vec<(usize, &TraversalResult)> = candidates
+                    .iter()
+                    .flat_map(|(start_idx, group)| group.iter().map(|result| (*start_idx, result)))
+                    .collect();
+                spans.sort_by_key(|(start_idx, result)| {
+                    (
+                        result.best_match_type(),
+                        Reverse(result.search_terms.len()),
+                        *start_idx,
+                    )
                 });
+
+                let mut covered = vec![false; slices.len()];
+                let mut selected = Vec::new();
+                for (start_idx, result) in spans {
+                    let range = start_idx..start_idx + result.search_terms.len();
+                    if covered[range.clone()].iter().any(|is_covered| *is_covered) {
+                        continue;
+                    }
+                    covered[range].fill(true);
+                    selected.push((start_idx, result));
+                }
+                selected.sort_by_key(|(start_idx, _)| *start_idx);
+                selected
+                    .into_iter()
+                    .map(|(start_idx, result)| result.to_search_result(self, start_idx, &offsets))
+                    .collect()
+            }
+            ResultSelection::AllMarkNested => {
+                let mut results = Vec::new();
+                // the largest end index of all previous spans, which all start at or before the
+                // current span
+                let mut max_end_idx = 0;
+                for (start_idx, group) in &candidates {
+                    for result in group.iter().rev() {
+                        let end_idx = start_idx + result.search_terms.len();
+                        let mut search_result = result.to_search_result(self, *start_idx, &offsets);
+                        search_result.nested = end_idx <= max_end_idx;
+                        max_end_idx = max_end_idx.max(end_idx);
+                        results.push(search_result);
+                    }
+                }
+                results
             }
-        }
-        if results.is_empty() {
-            Err(String::from("No matches found"))
-        } else {
-            Ok(results)
         }
     }
-}
 
-pub struct TraversalResult<'a> {
-    search_terms: Vec<String>,
-    search_results: &'a HashSet<Match>,
-}
+    /// Finds all keys in the given tokens in a single left-to-right pass. Returns the matches
+    /// grouped by their start index, each group ordered by increasing key length. Keys without
+    /// any match accepted by the corpus filter are skipped. If `max_gap` is positive, the gapped
+    /// matches are added as well, see `MatchType::Gapped`.
+    pub(crate) fn traverse<'a>(
+        &'a self,
+        slices: &'a [String],
+        offsets: &[(usize, usize)],
+        max_len: usize,
+        max_gap: usize,
+        corpus_filter: Option<&CorpusFilter>,
+    ) -> Vec<(usize, Vec<TraversalResult<'a>>)> {
+        let links = self.links();
+        let mut results: Vec<Vec<TraversalResult<'a>>> = Vec::new();
+        results.resize_with(slices.len(), Vec::new);
+
+        let mut state = ROOT;
+        for (idx, slice) in slices.iter().enumerate() {
+            let Some(token) = self.vocabulary.get(slice) else {
+                // no key contains an unknown token
+                state = ROOT;
+                continue;
+            };
+            loop {
+                if let Some(next) = self.edges.get(&(state, *token)) {
+                    state = *next;
+                    break;
+                }
+                if state == ROOT {
+                    break;
+                }
+                state = links.fail[state as usize];
+            }
 
-impl TraversalResult<'_> {
-    fn get_search_term_string(&self) -> String {
-        self.search_terms.join(" ")
-    }
-    fn get_search_results(&self) -> Vec<Match> {
-        self.search_results.iter().cloned().sorted().collect()
-    }
+            let mut node = if self.nodes[state as usize].matches.is_empty() {
+                links.output[state as usize]
+            } else {
+                state
+            };
+            while node != NO_NODE {
+                let depth = self.nodes[node as usize].depth as usize;
+                if depth <= max_len {
+                    let start_idx = idx + 1 - depth;
+                    let search_results: Vec<Candidate<'a>> = self.nodes[node as usize]
+                        .matches
+                        .iter()
+                        .filter_map(|mtch| {
+                            Candidate::from(mtch).restrict(|corpus| {
+                                corpus_filter.is_none_or(|filter| filter.accepts(corpus))
+                            })
+                        })
+                        .collect();
+                    if !search_results.is_empty() {
+                        results[start_idx].push(TraversalResult {
+                            search_terms: &slices[start_idx..=idx],
+                            search_results,
+                        });
+                    }
+                }
+                node = links.output[node as usize];
+            }
+        }
+
+        if self.fuzzy.is_enabled() {
+            let fuzzy_results = self.fuzzy_traverse(slices, max_len, corpus_filter);
+            merge_results(&mut results, slices, fuzzy_results);
+        }
+        if self.lazy_skip_grams.is_enabled() {
+            let skip_gram_results = self.lazy_skip_gram_traverse(slices, max_len, corpus_filter);
+            merge_results(&mut results, slices, skip_gram_results);
+        }
+        if max_gap > 0 {
+            let gapped_results =
+                self.gapped_traverse(slices, offsets, max_len, max_gap, corpus_filter);
+            merge_results(&mut results, slices, gapped_results);
+        }
+
+        results
+            .into_iter()
+            .enumerate()
+            .filter(|(_, results)| !results.is_empty())
+            .collect()
+    }
+
+    /// Finds the skip-grams of all entries of corpora with query-time skip-grams, i.e. paths
+    /// through the tree that start with the first token of a key and skip up to `max_skips` tree
+    /// tokens that are missing from the text. Returns the matches for each start index as
+    /// `(length, matches)`, ordered by increasing length.
+    fn lazy_skip_gram_traverse<'a>(
+        &'a self,
+        slices: &[String],
+        max_len: usize,
+        corpus_filter: Option<&CorpusFilter>,
+    ) -> Vec<Vec<(usize, Vec<Candidate<'a>>)>> {
+        let tokens: Vec<Option<u32>> = slices
+            .iter()
+            .map(|slice| self.vocabulary.get(slice).copied())
+            .collect();
+
+        (0..slices.len())
+            .into_par_iter()
+            .map(|start_idx| {
+                let mut found: Vec<(usize, Vec<Candidate<'a>>)> = Vec::new();
+                // the first token of an entry is never skipped
+                if let Some(child) =
+                    tokens[start_idx].and_then(|token| self.edges.get(&(ROOT, token)))
+                {
+                    let tokens = &tokens[start_idx..tokens.len().min(start_idx + max_len)];
+                    self.lazy_skip_gram_walk(
+                        tokens,
+                        *child,
+                        1,
+                        &mut Vec::new(),
+                        corpus_filter,
+                        &mut found,
+                    );
+                }
+                // keep the variant with the leftmost skipped positions for each entry, which is
+                // sorted first
+                let mut grouped = group_by_length(found);
+                for (_, matches) in &mut grouped {
+                    matches.dedup_by(|b, a| a.mtch == b.mtch);
+                }
+                grouped
+            })
+            .collect()
+    }
+
+    fn lazy_skip_gram_walk<'a>(
+        &'a self,
+        tokens: &[Option<u32>],
+        node: u32,
+        idx: usize,
+        skipped: &mut Vec<u32>,
+        corpus_filter: Option<&CorpusFilter>,
+        found: &mut Vec<(usize, Vec<Candidate<'a>>)>,
+    ) {
+        let depth = self.nodes[node as usize].depth as usize;
+        if !skipped.is_empty() {
+            let positions: Vec<usize> = skipped.iter().map(|idx| *idx as usize).collect();
+            // the `Full` labels of the document tokens, only looked up if needed
+            let key_labels: OnceLock<HashSet<&Arc<String>>> = OnceLock::new();
+            let matches: Vec<Candidate<'a>> = self.nodes[node as usize]
+                .matches
+                .iter()
+                .filter(|mtch| mtch.match_type == MatchType::Full)
+                .filter_map(|mtch| {
+                    Candidate {
+                        match_type: MatchType::SkipGram,
+                        skipped: Cow::Owned(skipped.clone()),
+                        ..Candidate::from(mtch)
+                    }
+                    .restrict(|corpus| {
+                        if corpus_filter.is_some_and(|filter| !filter.accepts(corpus))
+                            || !self.lazy_skip_grams.accepts(corpus, depth, skipped.len())
+                        {
+                            return false;
+                        }
+                        let policy = self.skip_gram_policy(corpus);
+                        if !policy.allows(depth, &positions)
+                            || !policy.within_max_variants(depth, &positions)
+                        {
+                            return false;
+                        }
+                        !policy.drop_collisions || {
+                            let labels =
+                                key_labels.get_or_init(|| self.full_labels(&tokens[..idx]));
+                            labels.is_empty() || labels.contains(&mtch.match_label)
+                        }
+                    })
+                })
+                .collect();
+            if !matches.is_empty() {
+                found.push((idx, matches));
+            }
+        }
+        if let Some(child) = tokens
+            .get(idx)
+            .copied()
+            .flatten()
+            .and_then(|token| self.edges.get(&(node, token)))
+        {
+            self.lazy_skip_gram_walk(tokens, *child, idx + 1, skipped, corpus_filter, found);
+        }
+        if skipped.len() < self.lazy_skip_grams.max_skips {
+            for child in self.children().get(node) {
+                skipped.push(depth as u32);
+                self.lazy_skip_gram_walk(tokens, *child, idx, skipped, corpus_filter, found);
+                skipped.pop();
+            }
+        }
+    }
+
+    /// The labels of the `Full` matches stored for exactly the given tokens.
+    fn full_labels(&self, tokens: &[Option<u32>]) -> HashSet<&Arc<String>> {
+        let mut node = ROOT;
+        for token in tokens {
+            match token.and_then(|token| self.edges.get(&(node, token))) {
+                Some(child) => node = *child,
+                None => return HashSet::new(),
+            }
+        }
+        self.nodes[node as usize]
+            .matches
+            .iter()
+            .filter(|mtch| mtch.match_type == MatchType::Full)
+            .map(|mtch| &mtch.match_label)
+            .collect()
+    }
+
+    fn children(&self) -> &Children {
+        self.children.get_or_init(|| {
+            let mut offsets = vec![0u32; self.nodes.len() + 1];
+            for node in self.nodes.iter().skip(1) {
+                offsets[node.parent as usize + 1] += 1;
+            }
+            for idx in 1..offsets.len() {
+                offsets[idx] += offsets[idx - 1];
+            }
+            let mut next = offsets.clone();
+            let mut children = vec![ROOT; self.nodes.len() - 1];
+            for (idx, node) in self.nodes.iter().enumerate().skip(1) {
+                let slot = &mut next[node.parent as usize];
+                children[*slot as usize] = idx as u32;
+                *slot += 1;
+            }
+            Children { offsets, children }
+        })
+    }
+
+    /// Finds all gapped matches, i.e. `Full` entries whose tokens occur in order in the text with
+    /// up to `max_gap` other tokens between them in total. Returns the matches for each start
+    /// index as `(length, matches)`, ordered by increasing length, where the length includes the
+    /// gap tokens.
+    fn gapped_traverse<'a>(
+        &'a self,
+        slices: &[String],
+        offsets: &[(usize, usize)],
+        max_len: usize,
+        max_gap: usize,
+        corpus_filter: Option<&CorpusFilter>,
+    ) -> Vec<Vec<(usize, Vec<Candidate<'a>>)>> {
+        let tokens: Vec<Option<u32>> = slices
+            .iter()
+            .map(|slice| self.vocabulary.get(slice).copied())
+            .collect();
+
+        (0..slices.len())
+            .into_par_iter()
+            .map(|start_idx| {
+                let mut found: Vec<(usize, Vec<Candidate<'a>>)> = Vec::new();
+                if let Some(child) =
+                    tokens[start_idx].and_then(|token| self.edges.get(&(ROOT, token)))
+                {
+                    self.gapped_walk(
+                        &tokens[start_idx..],
+                        &offsets[start_idx..],
+                        *child,
+                        1,
+                        1,
+                        &mut Vec::new(),
+                        max_len,
+                        max_gap,
+                        corpus_filter,
+                        &mut found,
+                    );
+                }
+                group_by_length(found)
+            })
+            .collect()
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn gapped_walk<'a>(
+        &'a self,
+        tokens: &[Option<u32>],
+        offsets: &[(usize, usize)],
+        node: u32,
+        idx: usize,
+        depth: usize,
+        gaps: &mut Vec<(usize, usize)>,
+        max_len: usize,
+        max_gap: usize,
+        corpus_filter: Option<&CorpusFilter>,
+        found: &mut Vec<(usize, Vec<Candidate<'a>>)>,
+    ) {
+        if depth >= max_len {
+            return;
+        }
+        for skip in 0..=max_gap - gaps.len() {
+            let next_idx = idx + skip;
+            let Some(token) = tokens.get(next_idx) else {
+                break;
+            };
+            let Some(child) = token.and_then(|token| self.edges.get(&(node, token))) else {
+                continue;
+            };
+            let gap_count = gaps.len();
+            gaps.extend_from_slice(&offsets[idx..next_idx]);
+            if !gaps.is_empty() {
+                let matches: Vec<Candidate<'a>> = self.nodes[*child as usize]
+                    .matches
+                    .iter()
+                    .filter(|mtch| mtch.match_type == MatchType::Full)
+                    .filter_map(|mtch| {
+                        Candidate {
+                            match_type: MatchType::Gapped,
+                            gaps: gaps.clone(),
+                            ..Candidate::from(mtch)
+                        }
+                        .restrict(|corpus| {
+                            corpus_filter.is_none_or(|filter| filter.accepts(corpus))
+                        })
+                    })
+                    .collect();
+                if !matches.is_empty() {
+                    found.push((next_idx + 1, matches));
+                }
+            }
+            self.gapped_walk(
+                tokens,
+                offsets,
+                *child,
+                next_idx + 1,
+                depth + 1,
+                gaps,
+                max_len,
+                max_gap,
+                corpus_filter,
+                found,
+            );
+            gaps.truncate(gap_count);
+        }
+    }
+
+    /// Finds all fuzzy matches, i.e. paths through the tree where at least one token is matched
+    /// within the edit distance of a fuzzy-enabled corpus. Returns the matches for each start
+    /// index as `(length, matches)`, ordered by increasing length.
+    fn fuzzy_traverse<'a>(
+        &'a self,
+        slices: &[String],
+        max_len: usize,
+        corpus_filter: Option<&CorpusFilter>,
+    ) -> Vec<Vec<(usize, Vec<Candidate<'a>>)>> {
+        // the exact and fuzzy candidates for each token as (id, distance, token length)
+        let mut cache: HashMap<&str, Vec<(u32, u32, usize)>> = HashMap::new();
+        let candidates: Vec<Vec<(u32, u32, usize)>> = slices
+            .iter()
+            .map(|slice| {
+                cache
+                    .entry(slice.as_str())
+                    .or_insert_with(|| {
+                        let mut candidates = Vec::new();
+                        if let Some(id) = self.vocabulary.get(slice) {
+                            candidates.push((*id, 0, usize::MAX));
+                        }
+                        candidates.extend(self.fuzzy.candidates(slice));
+                        candidates
+                    })
+                    .clone()
+            })
+            .collect();
+
+        (0..slices.len())
+            .into_par_iter()
+            .map(|start_idx| {
+                let mut found: Vec<(usize, Vec<Candidate<'a>>)> = Vec::new();
+                self.fuzzy_walk(
+                    &candidates[start_idx..candidates.len().min(start_idx + max_len)],
+                    ROOT,
+                    0,
+                    FuzzyPath::default(),
+                    corpus_filter,
+                    &mut found,
+                );
+                group_by_length(found)
+            })
+            .collect()
+    }
+
+    fn fuzzy_walk<'a>(
+        &'a self,
+        candidates: &[Vec<(u32, u32, usize)>],
+        node: u32,
+        idx: usize,
+        path: FuzzyPath,
+        corpus_filter: Option<&CorpusFilter>,
+        found: &mut Vec<(usize, Vec<Candidate<'a>>)>,
+    ) {
+        let Some(token_candidates) = candidates.get(idx) else {
+            return;
+        };
+        for (token, distance, token_length) in token_candidates {
+            let Some(child) = self.edges.get(&(node, *token)) else {
+                continue;
+            };
+            let path = path.step(*distance, *token_length);
+            if path.distance > 0 {
+                let matches: Vec<Candidate<'a>> = self.nodes[*child as usize]
+                    .matches
+                    .iter()
+                    .filter(|mtch| mtch.match_type == MatchType::Full)
+                    .filter_map(|mtch| {
+                        Candidate {
+                            match_type: MatchType::Fuzzy,
+                            distance: path.distance,
+                            ..Candidate::from(mtch)
+                        }
+                        .restrict(|corpus| {
+                            corpus_filter.is_none_or(|filter| filter.accepts(corpus))
+                                && self.fuzzy.accepts(corpus, &path)
+                        })
+                    })
+                    .collect();
+                if !matches.is_empty() {
+                    found.push((idx + 1, matches));
+                }
+            }
+            self.fuzzy_walk(candidates, *child, idx + 1, path, corpus_filter, found);
+        }
+    }
+
+    /// Sets the score of all matches and drops those scoring below `min_score`. Spans without any
+    /// remaining matches are removed.
+    fn annotate<'a>(
+        &self,
+        candidates: Vec<(usize, Vec<TraversalResult<'a>>)>,
+        min_score: Option<f64>,
+    ) -> Vec<(usize, Vec<TraversalResult<'a>>)> {
+        candidates
+            .into_iter()
+            .filter_map(|(start_idx, mut group)| {
+                for result in &mut group {
+                    result.score(&self.score_weights);
+                    result.search_results.retain(|candidate| {
+                        min_score.is_none_or(|min_score| candidate.score >= min_score)
+                    });
+                }
+                group.retain(|result| !result.search_results.is_empty());
+                (!group.is_empty()).then_some((start_idx, group))
+            })
+            .collect()
+    }
+
+    /// Sets the weights of the match types in `SearchMatch::score`.
+    pub fn set_score_weights(&mut self, score_weights: ScoreWeights) {
+        self.score_weights = score_weights;
+    }
+
+    /// Sets the largest `SearchOptions::max_gap` a search may use.
+    pub fn set_max_gap(&mut self, max_gap: usize) {
+        self.max_gap = max_gap;
+    }
+
+    /// The largest `SearchOptions::max_gap` a search may use, larger values are clamped.
+    #[must_use]
+    pub fn max_gap(&self) -> usize {
+        self.max_gap
+    }
+
+    fn links(&self) -> &Links {
+        self.links.get_or_init(|| self.build_links())
+    }
+
+    /// Computes the failure and output links of all nodes in breadth-first order.
+    fn build_links(&self) -> Links {
+        let mut fail = vec![ROOT; self.nodes.len()];
+        let mut output = vec![NO_NODE; self.nodes.len()];
+        let order = (1..self.nodes.len()).sorted_by_key(|&idx| self.nodes[idx].depth);
+        for idx in order {
+            let node = &self.nodes[idx];
+            if node.parent != ROOT {
+                let mut state = fail[node.parent as usize];
+                loop {
+                    if let Some(next) = self.edges.get(&(state, node.token)) {
+                        fail[idx] = *next;
+                        break;
+                    }
+                    if state == ROOT {
+                        break;
+                    }
+                    state = fail[state as usize];
+                }
+            }
+            let fail_node = fail[idx];
+            output[idx] = if fail_node != ROOT && !self.nodes[fail_node as usize].matches.is_empty()
+            {
+                fail_node
+            } else {
+                output[fail_node as usize]
+            };
+        }
+        Links { fail, output }
+    }
+}
+
+/// Groups the matches found for a single start index by their length, ordered by increasing
+/// length.
+fn group_by_length(mut found: Vec<(usize, Vec<Candidate>)>) -> Vec<(usize, Vec<Candidate>)> {
+    found.sort_by_key(|(len, _)| *len);
+    found
+        .into_iter()
+        .chunk_by(|(len, _)| *len)
+        .into_iter()
+        .map(|(len, group)| {
+            let mut matches: Vec<Candidate> = group.flat_map(|(_, matches)| matches).collect();
+            matches.sort_by(|a, b| a.key().cmp(&b.key()));
+            matches.dedup_by(|b, a| a.key() == b.key());
+            (len, matches)
+        })
+        .collect()
+}
+
+/// Merges additional matches, given for each start index as `(length, matches)` ordered by
+/// increasing length, into the traversal results.
+fn merge_results<'a>(
+    results: &mut [Vec<TraversalResult<'a>>],
+    slices: &'a [String],
+    additional: Vec<Vec<(usize, Vec<Candidate<'a>>)>>,
+) {
+    for (start_idx, additional) in additional.into_iter().enumerate() {
+        let results = &mut results[start_idx];
+        for (len, matches) in additional {
+            match results.binary_search_by_key(&len, |result| result.search_terms.len()) {
+                Ok(idx) => results[idx].search_results.extend(matches),
+                Err(idx) => results.insert(
+                    idx,
+                    TraversalResult {
+                        search_terms: &slices[start_idx..start_idx + len],
+                        search_results: matches,
+                    },
+                ),
+            }
+        }
+    }
+}
+
+/// Drops all `Abbreviated` matches that are not licensed by a `Full` match of the same entry
+/// starting earlier in the text and records the first such match on the others. `Full` matches
+/// with a score below `min_score` are not returned and therefore do not license any matches. Spans
+/// without any remaining matches are removed.
+fn license_abbreviations<'a>(
+    candidates: Vec<(usize, Vec<TraversalResult<'a>>)>,
+    offsets: &[(usize, usize)],
+    score_weights: &ScoreWeights,
+    min_score: Option<f64>,
+) -> Vec<(usize, Vec<TraversalResult<'a>>)> {
+    let mut licenses: HashMap<Arc<String>, (usize, usize)> = HashMap::new();
+    candidates
+        .into_iter()
+        .filter_map(|(start_idx, mut group)| {
+            for result in &mut group {
+                result.search_results.retain_mut(|candidate| {
+                    if candidate.match_type != MatchType::Abbreviated {
+                        return true;
+                    }
+                    match licenses.get(&candidate.mtch.match_string) {
+                        Some(span) => {
+                            candidate.licensed_by = Some(*span);
+                            true
+                        }
+                        None => false,
+                    }
+                });
+            }
+            group.retain(|result| !result.search_results.is_empty());
+            for result in &mut group {
+                let span = (
+                    offsets[start_idx].0,
+                    offsets[start_idx + result.search_terms.len() - 1].1,
+                );
+                // the matches of the span are final, so are their scores
+                result.score(score_weights);
+                for candidate in &result.search_results {
+                    if candidate.match_type == MatchType::Full
+                        && min_score.is_none_or(|min_score| candidate.score >= min_score)
+                    {
+                        licenses
+                            .entry(candidate.mtch.match_string.clone())
+                            .or_insert(span);
+                    }
+                }
+            }
+            (!group.is_empty()).then_some((start_idx, group))
+        })
+        .collect()
+}
+
+/// A match found while traversing the text, which refers to its entry in the tree until it is
+/// turned into a `SearchMatch`.
+#[derive(Debug, Clone)]
+struct Candidate<'a> {
+    mtch: &'a Match,
+    /// The provenances of the entry from the corpora that allow this candidate.
+    provenances: Cow<'a, [Arc<Provenance>]>,
+    match_type: MatchType,
+    distance: u32,
+    skipped: Cow<'a, [u32]>,
+    gaps: Vec<(usize, usize)>,
+    licensed_by: Option<(usize, usize)>,
+    score: f64,
+}
+
+impl<'a> From<&'a Match> for Candidate<'a> {
+    fn from(mtch: &'a Match) -> Self {
+        Candidate {
+            mtch,
+            provenances: Cow::Borrowed(&mtch.provenances),
+            match_type: mtch.match_type.clone(),
+            distance: 0,
+            skipped: Cow::Borrowed(&mtch.skipped),
+            gaps: Vec::new(),
+            licensed_by: None,
+            score: 0.0,
+        }
+    }
+}
+
+impl Candidate<'_> {
+    /// Keeps only the provenances whose corpus is accepted, or drops the candidate if none
+    /// remains. Candidates without provenance are kept if `None` is accepted.
+    fn restrict(mut self, accepts: impl Fn(Option<&str>) -> bool) -> Option<Self> {
+        if self.provenances.is_empty() {
+            return accepts(None).then_some(self);
+        }
+        if !self
+            .provenances
+            .iter()
+            .all(|provenance| accepts(Some(&provenance.corpus)))
+        {
+            let accepted: Vec<Arc<Provenance>> = self
+                .provenances
+                .iter()
+                .filter(|provenance| accepts(Some(&provenance.corpus)))
+                .cloned()
+                .collect();
+            if accepted.is_empty() {
+                return None;
+            }
+            self.provenances = Cow::Owned(accepted);
+        }
+        Some(self)
+    }
+
+    /// Identifies the candidate, ordered by its entry first.
+    #[allow(clippy::type_complexity)]
+    fn key(&self) -> (&Match, &MatchType, u32, &[u32], &[(usize, usize)]) {
+        (
+            self.mtch,
+            &self.match_type,
+            self.distance,
+            &self.skipped,
+            &self.gaps,
+        )
+    }
+
+    fn to_search_match(&self, tree: &HashMapSearchTree) -> SearchMatch {
+        let skipped_tokens = if self.skipped.is_empty() {
+            Vec::new()
+        } else {
+            let tokens = tree.tokenize(&self.mtch.match_string).0;
+            self.skipped
+                .iter()
+                .filter_map(|idx| tokens.get(*idx as usize).cloned())
+                .collect()
+        };
+        SearchMatch {
+            match_type: self.match_type.clone(),
+            match_string: self.mtch.match_string.clone(),
+            match_label: self.mtch.match_label.clone(),
+            provenances: self.provenances.to_vec(),
+            distance: self.distance,
+            skipped: self.skipped.to_vec(),
+            skipped_tokens,
+            gaps: self.gaps.clone(),
+            licensed_by: self.licensed_by,
+            score: self.score,
+        }
+    }
+}
+
+pub struct TraversalResult<'a> {
+    search_terms: &'a [String],
+    search_results: Vec<Candidate<'a>>,
+}
+
+impl TraversalResult<'_> {
+    fn get_search_term_string(&self) -> String {
+        self.search_terms.join(" ")
+    }
+    /// Scores the candidates, see `ScoreWeights::score`.
+    fn score(&mut self, score_weights: &ScoreWeights) {
+        let span_length = self.search_terms.len();
+        let labels = self
+            .search_results
+            .iter()
+            .map(|candidate| &candidate.mtch.match_label)
+            .unique()
+            .count();
+        for candidate in &mut self.search_results {
+            candidate.score = score_weights.score(
+                &candidate.match_type,
+                span_length - candidate.gaps.len(),
+                candidate.skipped.len() + candidate.gaps.len(),
+                labels,
+            );
+        }
+    }
+
+    fn best_match_type(&self) -> Option<&MatchType> {
+        self.search_results
+            .iter()
+            .map(|candidate| &candidate.match_type)
+            .min()
+    }
+    fn to_search_result(
+        &self,
+        tree: &HashMapSearchTree,
+        start_idx: usize,
+        offsets: &[(usize, usize)],
+    ) -> SearchResult {
+        SearchResult {
+            string: self.get_search_term_string(),
+            matches: self
+                .search_results
+                .iter()
+                .map(|candidate| candidate.to_search_match(tree))
+                .collect(),
+            begin: offsets[start_idx].0,
+            end: offsets[start_idx + self.search_terms.len() - 1].1,
+            nested: false,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -467,6 +1944,19 @@ mod test {
     use itertools::Itertools;
 
     use super::*;
+    use crate::skip_gram::SkipGramPositions;
+    use crate::util::{CorpusFormat, PreTokenizerKind, TempFile, TokenizerConfig};
+
+    /// A provenance of an entry of the given corpus, read from the first row of `<corpus>.tsv`.
+    fn provenance(corpus: &str) -> Arc<Provenance> {
+        Arc::new(Provenance {
+            corpus: Arc::new(corpus.to_string()),
+            source: Arc::new(format!("{corpus}.tsv")),
+            row: 1,
+            metadata: Metadata::default(),
+            synonym: false,
+        })
+    }
 
     #[test]
     fn test_sample() {
@@ -482,42 +1972,82 @@ mod test {
         tree.load(entries.clone(), false, 0, 0, false, 0, 3);
         let tree = tree;
 
-        println!("{:?}", tree.search_map);
+        println!("{:?}", tree.nodes);
 
-        let results = tree.search("An xyz", Some(3), None);
+        let results = tree.search(
+            "An xyz",
+            &SearchOptions {
+                max_len: Some(3),
+                ..Default::default()
+            },
+        );
         assert!(results.is_empty());
 
-        let results = tree.search(&an_example, Some(3), Some(&ResultSelection::Last));
+        let results = tree.search(
+            &an_example,
+            &SearchOptions {
+                max_len: Some(3),
+                result_selection: Some(ResultSelection::Last),
+                ..Default::default()
+            },
+        );
         println!("{results:?}");
         let results = results.first().unwrap();
-        let results = &results.1;
+        let results = &results.matches;
         assert_eq!(results.len(), 1);
         assert_eq!(&*results[0].match_label, &entries[0].1);
 
-        let results = tree.search(&an_example_phrase, Some(3), Some(&ResultSelection::Last));
+        let results = tree.search(
+            &an_example_phrase,
+            &SearchOptions {
+                max_len: Some(3),
+                result_selection: Some(ResultSelection::Last),
+                ..Default::default()
+            },
+        );
         println!("{results:?}");
         let results = results.first().unwrap();
-        let matches = &results.1;
+        let matches = &results.matches;
         assert_eq!(matches.len(), 1);
         assert_eq!(&*matches[0].match_label, &entries[1].1);
 
-        let results = tree.search(&example, Some(3), None);
+        let results = tree.search(
+            &example,
+            &SearchOptions {
+                max_len: Some(3),
+                ..Default::default()
+            },
+        );
         println!("{results:?}");
         let results = results.first().unwrap();
-        let results = &results.1;
+        let results = &results.matches;
         assert_eq!(results.len(), 1);
         assert_eq!(&*results[0].match_label, &entries[2].1);
 
-        let results = tree.search(&an_example_phrase, Some(2), Some(&ResultSelection::Last));
+        let results = tree.search(
+            &an_example_phrase,
+            &SearchOptions {
+                max_len: Some(2),
+                result_selection: Some(ResultSelection::Last),
+                ..Default::default()
+            },
+        );
         println!("{results:?}");
         let results = results.first().unwrap();
-        let matches = &results.1;
+        let matches = &results.matches;
         assert_eq!(matches.len(), 1);
         assert_eq!(&*matches[0].match_label, &entries[0].1);
 
-        let results = tree.search(&an_example_phrase, Some(3), Some(&ResultSelection::All));
+        let results = tree.search(
+            &an_example_phrase,
+            &SearchOptions {
+                max_len: Some(3),
+                result_selection: Some(ResultSelection::All),
+                ..Default::default()
+            },
+        );
         println!("{results:?}");
-        let matches: Vec<_> = results.into_iter().flat_map(|r| r.1).collect();
+        let matches: Vec<_> = results.into_iter().flat_map(|r| r.matches).collect();
         assert_eq!(matches.len(), 3);
         let match_labels: Vec<String> = matches
             .into_iter()
@@ -541,39 +2071,78 @@ mod test {
         tree.load(entries.clone(), true, 2, 2, false, 0, 3);
         let tree = tree;
 
-        println!("{:?}", tree.search_map);
+        println!("{:?}", tree.nodes);
 
-        let results = tree.search("An xyz", Some(3), None);
+        let results = tree.search(
+            "An xyz",
+            &SearchOptions {
+                max_len: Some(3),
+                ..Default::default()
+            },
+        );
         assert!(results.is_empty());
 
-        let results = tree.search("An A A xyz ", Some(3), None);
+        let results = tree.search(
+            "An A A xyz ",
+            &SearchOptions {
+                max_len: Some(3),
+                ..Default::default()
+            },
+        );
         assert!(results.is_empty());
 
-        let results: Vec<(String, Vec<crate::tree::Match>, usize, usize)> =
-            tree.search(&entries[0].0, Some(3), Some(&ResultSelection::Last));
+        let results: Vec<SearchResult> = tree.search(
+            &entries[0].0,
+            &SearchOptions {
+                max_len: Some(3),
+                result_selection: Some(ResultSelection::Last),
+                ..Default::default()
+            },
+        );
         println!("{results:?}");
         let results = results.first().unwrap();
-        let results = &results.1;
+        let results = &results.matches;
         assert_eq!(results.len(), 2);
         assert_eq!(&*results[0].match_label, &entries[0].1);
 
-        let results = tree.search(&entries[1].0, Some(3), Some(&ResultSelection::Last));
+        let results = tree.search(
+            &entries[1].0,
+            &SearchOptions {
+                max_len: Some(3),
+                result_selection: Some(ResultSelection::Last),
+                ..Default::default()
+            },
+        );
         println!("{results:?}");
         let results = results.first().unwrap();
-        let matches = &results.1;
+        let matches = &results.matches;
         assert_eq!(matches.len(), 1);
         assert_eq!(&*matches[0].match_label, &entries[1].1);
 
-        let results = tree.search(&entries[1].0, Some(2), Some(&ResultSelection::Last));
+        let results = tree.search(
+            &entries[1].0,
+            &SearchOptions {
+                max_len: Some(2),
+                result_selection: Some(ResultSelection::Last),
+                ..Default::default()
+            },
+        );
         println!("{results:?}");
         let results = results.first().unwrap();
-        let matches = &results.1;
+        let matches = &results.matches;
         assert_eq!(matches.len(), 2);
         assert_eq!(&*matches[0].match_label, &entries[0].1);
 
-        let results = tree.search(&entries[1].0, Some(3), Some(&ResultSelection::All));
+        let results = tree.search(
+            &entries[1].0,
+            &SearchOptions {
+                max_len: Some(3),
+                result_selection: Some(ResultSelection::All),
+                ..Default::default()
+            },
+        );
         println!("{results:?}");
-        let matches: Vec<_> = results.into_iter().flat_map(|r| r.1).collect();
+        let matches: Vec<_> = results.into_iter().flat_map(|r| r.matches).collect();
         assert_eq!(matches.len(), 3);
         let match_labels: Vec<String> = matches
             .into_iter()
@@ -584,5 +2153,758 @@ mod test {
             match_labels,
             vec!["uri:example", "uri:phrase", "uri:phrase"]
         );
+
+        let results = tree.search("An phrase", &SearchOptions::default());
+        let mtch = &results.first().unwrap().matches[0];
+        assert_eq!(mtch.match_type, MatchType::SkipGram);
+        assert_eq!(mtch.skipped, vec![1]);
+        assert_eq!(mtch.skipped_tokens, vec!["example"]);
+    }
+
+    #[test]
+    fn test_traverse_matches_all_windows() {
+        let mut tree = HashMapSearchTree::default();
+        let entries: Vec<(String, String)> = vec![
+            ("Sula".to_string(), "uri:sula".to_string()),
+            ("Sula bassana".to_string(), "uri:bassana".to_string()),
+            ("bassana".to_string(), "uri:bassana-only".to_string()),
+            (
+                "Sula leucogaster leucogaster".to_string(),
+                "uri:sll".to_string(),
+            ),
+            ("leucogaster".to_string(), "uri:leucogaster".to_string()),
+            ("leucogaster Sula".to_string(), "uri:ls".to_string()),
+        ];
+        tree.load(entries, true, 1, 2, true, 0, 3);
+
+        let text = "Sula leucogaster leucogaster Sula bassana x leucogaster Sula S. bassana";
+        let (slices, offsets) = tree.tokenize(text);
+        for max_len in 1..=4 {
+            let actual: Vec<(usize, usize, Vec<Match>)> = tree
+                .traverse(&slices, &offsets, max_len, 0, None)
+                .into_iter()
+                .flat_map(|(start, results)| {
+                    results.into_iter().map(move |result| {
+                        (
+                            start,
+                            result.search_terms.len(),
+                            result
+                                .search_results
+                                .iter()
+                                .map(|candidate| candidate.mtch.clone())
+                                .collect::<Vec<Match>>(),
+                        )
+                    })
+                })
+                .collect();
+
+            let mut expected = Vec::new();
+            for start in 0..slices.len() {
+                for len in 1..=max_len.min(slices.len() - start) {
+                    if let Some(matches) = tree.get(&slices[start..start + len]) {
+                        expected.push((start, len, matches.to_vec()));
+                    }
+                }
+            }
+            assert_eq!(actual, expected);
+        }
+    }
+
+    #[test]
+    fn test_corpus_filter() {
+        let mut tree = HashMapSearchTree::default();
+        tree.load_with_provenance(
+            vec![
+                (
+                    "Sula".to_string(),
+                    "uri:a".to_string(),
+                    Some(provenance("a")),
+                ),
+                (
+                    "Sula".to_string(),
+                    "uri:b".to_string(),
+                    Some(provenance("b")),
+                ),
+                (
+                    "Sula bassana".to_string(),
+                    "uri:b".to_string(),
+                    Some(provenance("b")),
+                ),
+            ],
+            false,
+            0,
+            0,
+            false,
+            0,
+            3,
+        );
+
+        let labels = |filter: &CorpusFilter| {
+            tree.search(
+                "Sula bassana",
+                &SearchOptions {
+                    result_selection: Some(ResultSelection::All),
+                    corpus_filter: Some(filter.clone()),
+                    ..Default::default()
+                },
+            )
+            .into_iter()
+            .flat_map(|r| r.matches)
+            .map(|mtch| (*mtch.match_label).clone())
+            .collect::<Vec<String>>()
+        };
+
+        let include_a = CorpusFilter {
+            include: Some(HashSet::from(["a".to_string()])),
+            exclude: None,
+        };
+        assert_eq!(labels(&include_a), vec!["uri:a"]);
+
+        let exclude_a = CorpusFilter {
+            include: None,
+            exclude: Some(HashSet::from(["a".to_string()])),
+        };
+        assert_eq!(labels(&exclude_a), vec!["uri:b", "uri:b"]);
+
+        // the longest match is selected among the accepted matches only
+        let results = tree.search(
+            "Sula bassana",
+            &SearchOptions {
+                corpus_filter: Some(include_a.clone()),
+                ..Default::default()
+            },
+        );
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].string, "sula");
+    }
+
+    #[test]
+    fn test_merged_provenances() {
+        let file = TempFile::new(
+            "provenance.tsv",
+            "# exported 2024-01-01\n\
+            # license: CC0\n\
+            name\tid\n\
+            Sula\turi:sula\n\
+            Sula bassana\turi:bassana\n\
+            Sula\turi:sula\n",
+        );
+        let format = Some(CorpusFormat {
+            has_header: Some(true),
+            skip_lines: Some(2),
+            ..Default::default()
+        });
+        let mut tree = HashMapSearchTree::default();
+        let path = file.path();
+        for corpus in ["a", "b"] {
+            tree.load_file(corpus, path, false, 0, 0, &None, false, 0, 3, &format)
+                .unwrap();
+        }
+
+        let provenances = |mtch: &Match| {
+            mtch.provenances
+                .iter()
+                .map(|provenance| {
+                    assert_eq!(*provenance.source, path);
+                    format!("{}:{}", provenance.corpus, provenance.row)
+                })
+                .collect::<Vec<String>>()
+        };
+        // identical entries from different rows and corpora are stored once
+        let matches = tree.get(&["sula".to_string()]).unwrap();
+        assert_eq!(matches.len(), 1);
+        assert_eq!(provenances(&matches[0]), vec!["a:4", "a:6", "b:4", "b:6"]);
+        let matches = tree
+            .get(&["sula".to_string(), "bassana".to_string()])
+            .unwrap();
+        assert_eq!(provenances(&matches[0]), vec!["a:5", "b:5"]);
+
+        // results keep the provenances of the accepted corpora only
+        let results = tree.search(
+            "Sula",
+            &SearchOptions {
+                corpus_filter: Some(CorpusFilter {
+                    include: None,
+                    exclude: Some(HashSet::from(["a".to_string()])),
+                }),
+                ..Default::default()
+            },
+        );
+        assert_eq!(results[0].matches.len(), 1);
+        assert_eq!(
+            results[0].matches[0].corpora().collect::<Vec<_>>(),
+            vec!["b"]
+        );
+    }
+
+    fn overlap_tree() -> HashMapSearchTree {
+        let mut tree = HashMapSearchTree::default();
+        let entries = [
+            ("a b", MatchType::SkipGram),
+            ("b c d", MatchType::Full),
+            ("c d", MatchType::Full),
+            ("c", MatchType::Full),
+            ("d e", MatchType::Full),
+        ];
+        for (entry, match_type) in entries {
+            tree.insert(
+                entry.split(' ').map(String::from).collect(),
+                Arc::new(entry.to_string()),
+                Arc::new(format!("uri:{entry}")),
+                match_type,
+                None,
+            );
+        }
+        tree
+    }
+
+    fn spans(results: &[SearchResult]) -> Vec<(&str, bool)> {
+        results
+            .iter()
+            .map(|result| (result.string.as_str(), result.nested))
+            .collect()
+    }
+
+    #[test]
+    fn test_leftmost_longest() {
+        let tree = overlap_tree();
+        let results = tree.search(
+            "a b c d e",
+            &SearchOptions {
+                result_selection: Some(ResultSelection::LeftmostLongest),
+                ..Default::default()
+            },
+        );
+        assert_eq!(spans(&results), vec![("a b", false), ("c d", false)]);
+    }
+
+    #[test]
+    fn test_highest_priority_longest() {
+        let tree = overlap_tree();
+        let results = tree.search(
+            "a b c d e",
+            &SearchOptions {
+                result_selection: Some(ResultSelection::HighestPriorityLongest),
+                ..Default::default()
+            },
+        );
+        assert_eq!(spans(&results), vec![("b c d", false)]);
+
+        // without the long Full match, the partially overlapping "d e" loses against the
+        // leftmost "c d" of the same priority and length
+        let results = tree.search(
+            "c d e",
+            &SearchOptions {
+                result_selection: Some(ResultSelection::HighestPriorityLongest),
+                ..Default::default()
+            },
+        );
+        assert_eq!(spans(&results), vec![("c d", false)]);
+    }
+
+    #[test]
+    fn test_all_mark_nested() {
+        let tree = overlap_tree();
+        let results = tree.search(
+            "a b c d e",
+            &SearchOptions {
+                result_selection: Some(ResultSelection::AllMarkNested),
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            spans(&results),
+            vec![
+                ("a b", false),
+                ("b c d", false),
+                ("c d", true),
+                ("c", true),
+                ("d e", false),
+            ]
+        );
+        assert_eq!((results[1].begin, results[1].end), (2, 7));
+    }
+
+    #[test]
+    fn test_fuzzy() {
+        let mut tree = HashMapSearchTree::default();
+        tree.load_with_provenance(
+            vec![
+                (
+                    "Puffinus puffinus".to_string(),
+                    "uri:puffinus".to_string(),
+                    Some(provenance("fuzzy")),
+                ),
+                (
+                    "Sula".to_string(),
+                    "uri:sula".to_string(),
+                    Some(provenance("fuzzy")),
+                ),
+                (
+                    "Alcidae".to_string(),
+                    "uri:alcidae".to_string(),
+                    Some(provenance("exact")),
+                ),
+            ],
+            false,
+            0,
+            0,
+            false,
+            0,
+            3,
+        );
+        tree.enable_fuzzy(
+            "fuzzy",
+            FuzzyOptions {
+                max_distance: 1,
+                min_token_length: 5,
+            },
+        );
+
+        let results = tree.search("Puffinis puffinus", &SearchOptions::default());
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].matches.len(), 1);
+        assert_eq!(results[0].matches[0].match_type, MatchType::Fuzzy);
+        assert_eq!(results[0].matches[0].distance, 1);
+        assert_eq!(&*results[0].matches[0].match_label, "uri:puffinus");
+        assert_eq!((results[0].begin, results[0].end), (0, 17));
+
+        let results = tree.search("Puffinis pufinus", &SearchOptions::default());
+        assert_eq!(results[0].matches[0].distance, 2);
+
+        // the exact match is preferred
+        let results = tree.search("Puffinus puffinus", &SearchOptions::default());
+        assert_eq!(results[0].matches[0].match_type, MatchType::Full);
+
+        // too many edits in a token, too short tokens and corpora without fuzzy matching
+        assert!(tree
+            .search("Pufinnis puffinus", &SearchOptions::default())
+            .is_empty());
+        assert!(tree.search("Sulu", &SearchOptions::default()).is_empty());
+        assert!(tree.search("Alcidea", &SearchOptions::default()).is_empty());
+    }
+
+    #[test]
+    fn test_license_abbreviations() {
+        let mut tree = HashMapSearchTree::default();
+        let entries: Vec<(String, String)> = vec![
+            ("Puffinus puffinus".to_string(), "uri:puffinus".to_string()),
+            (
+                "Pelecanus puffinus".to_string(),
+                "uri:pelecanus".to_string(),
+            ),
+        ];
+        tree.load(entries, false, 0, 0, true, 0, 3);
+
+        let text = "P. puffinus is rare. Puffinus puffinus and P. puffinus";
+        let results = tree.search(text, &SearchOptions::default());
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].matches.len(), 2);
+
+        let options = SearchOptions {
+            license_abbreviations: true,
+            ..Default::default()
+        };
+        let results = tree.search(text, &options);
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].matches[0].match_type, MatchType::Full);
+        assert_eq!(results[0].matches[0].licensed_by, None);
+        assert_eq!(results[1].matches.len(), 1);
+        assert_eq!(results[1].matches[0].match_type, MatchType::Abbreviated);
+        assert_eq!(&*results[1].matches[0].match_label, "uri:puffinus");
+        assert_eq!(
+            results[1].matches[0].licensed_by,
+            Some((results[0].begin, results[0].end))
+        );
+
+        // a full match below the minimum score licenses nothing
+        tree.set_score_weights(ScoreWeights {
+            full: 0.3,
+            ..Default::default()
+        });
+        let options = SearchOptions {
+            license_abbreviations: true,
+            min_score: Some(0.4),
+            ..Default::default()
+        };
+        assert!(tree.search(text, &options).is_empty());
+    }
+
+    #[test]
+    fn test_abbreviation_options() {
+        let tokenizer = TokenizerConfig {
+            pre_tokenizers: Some(vec![PreTokenizerKind::Bert]),
+            ..Default::default()
+        }
+        .build()
+        .unwrap();
+        let mut tree = HashMapSearchTree::with_tokenizer(tokenizer);
+        tree.set_abbreviation_options(
+            "corpus",
+            AbbreviationOptions {
+                max_segments: 2,
+                prefix_lengths: vec![1, 4],
+                period: true,
+            },
+        );
+        let provenance = provenance("corpus");
+        let entries = vec![(
+            "Puffinus puffinus puffinus".to_string(),
+            "uri:puffinus".to_string(),
+            Some(provenance),
+        )];
+        tree.load_with_provenance(entries, false, 0, 0, true, 1, 3);
+
+        let text = "P. p. puffinus and Puff. puffinus puffinus, but not P p puffinus";
+        let results = tree.search(text, &SearchOptions::default());
+        let found: Vec<(&str, &MatchType)> = results
+            .iter()
+            .map(|result| {
+                (
+                    &text[result.begin..result.end],
+                    &result.matches[0].match_type,
+                )
+            })
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                ("P. p. puffinus", &MatchType::Abbreviated),
+                ("Puff. puffinus puffinus", &MatchType::Abbreviated),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_taxon_variants() {
+        let mut tree = HashMapSearchTree::default();
+        tree.enable_taxon_variants("corpus", TaxonVariantOptions::default());
+        let provenance = provenance("corpus");
+        let entries: Vec<(String, String, Option<Arc<Provenance>>)> = [
+            (
+                "Puffinus (Puffinus) puffinus (Brünnich, 1764)",
+                "uri:puffinus",
+            ),
+            ("Homo sapiens subsp. sapiens Linnaeus, 1758", "uri:homo"),
+        ]
+        .into_iter()
+        .map(|(search_term, label)| {
+            (
+                search_term.to_string(),
+                label.to_string(),
+                Some(provenance.clone()),
+            )
+        })
+        .collect();
+        let summary = tree.load_with_provenance(entries, false, 0, 0, false, 0, 3);
+        // "Puffinus Puffinus puffinus", "Puffinus puffinus", "Homo sapiens subsp sapiens" and
+        // "Homo sapiens sapiens"
+        assert_eq!(summary.taxon_variants, 4);
+
+        let text = "Puffinus puffinus and Homo sapiens sapiens";
+        let results = tree.search(text, &SearchOptions::default());
+        let found: Vec<(&str, &MatchType, &str)> = results
+            .iter()
+            .map(|result| {
+                let mtch = &result.matches[0];
+                (
+                    &text[result.begin..result.end],
+                    &mtch.match_type,
+                    mtch.match_string.as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (
+                    "Puffinus puffinus",
+                    &MatchType::Variant,
+                    "Puffinus (Puffinus) puffinus (Brünnich, 1764)"
+                ),
+                (
+                    "Homo sapiens sapiens",
+                    &MatchType::Variant,
+                    "Homo sapiens subsp. sapiens Linnaeus, 1758"
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_score() {
+        let mut tree = HashMapSearchTree::default();
+        let entries: Vec<(String, String)> = vec![
+            ("Puffinus puffinus".to_string(), "uri:puffinus".to_string()),
+            (
+                "Pelecanus puffinus".to_string(),
+                "uri:pelecanus".to_string(),
+            ),
+            (
+                "Sula leucogaster plotus".to_string(),
+                "uri:sula".to_string(),
+            ),
+        ];
+        tree.load(entries, true, 2, 1, true, 0, 3);
+
+        let scores = |text: &str, min_score: Option<f64>| {
+            let options = SearchOptions {
+                result_selection: Some(ResultSelection::All),
+                min_score,
+                ..Default::default()
+            };
+            tree.search(text, &options)
+                .into_iter()
+                .flat_map(|result| result.matches)
+                .map(|mtch| (mtch.match_type, mtch.score))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            scores("Puffinus puffinus", None),
+            vec![(MatchType::Full, 2.0 / 3.0)]
+        );
+        // two labels share the abbreviated key
+        assert_eq!(
+            scores("P. puffinus", None),
+            vec![
+                (MatchType::Abbreviated, 0.8 * 2.0 / 3.0 / 2.0),
+                (MatchType::Abbreviated, 0.8 * 2.0 / 3.0 / 2.0)
+            ]
+        );
+        // one of three tokens was skipped
+        assert_eq!(
+            scores("Sula plotus", None),
+            vec![(MatchType::SkipGram, 0.6 * 2.0 / 3.0 / 2.0)]
+        );
+        assert_eq!(
+            scores("Sula leucogaster plotus", None).last(),
+            Some(&(MatchType::Full, 0.75))
+        );
+        assert!(scores("Sula plotus", Some(0.5)).is_empty());
+    }
+
+    #[test]
+    fn test_gapped() {
+        let mut tree = HashMapSearchTree::default();
+        let entries: Vec<(String, String)> = vec![
+            ("Sula bassana".to_string(), "uri:bassana".to_string()),
+            ("Puffinus puffinus".to_string(), "uri:puffinus".to_string()),
+        ];
+        tree.load(entries, false, 0, 0, false, 0, 3);
+
+        let text = "Sula (Morus) bassana and Puffinus p. puffinus";
+        assert!(tree.search(text, &SearchOptions::default()).is_empty());
+
+        let options = SearchOptions {
+            max_gap: Some(1),
+            ..Default::default()
+        };
+        let results = tree.search(text, &options);
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].string, "sula morus bassana");
+        assert_eq!((results[0].begin, results[0].end), (0, 20));
+        assert_eq!(results[0].matches[0].match_type, MatchType::Gapped);
+        assert_eq!(&*results[0].matches[0].match_label, "uri:bassana");
+        assert_eq!(results[0].matches[0].gaps, vec![(6, 11)]);
+        assert_eq!((results[1].begin, results[1].end), (25, 45));
+        assert_eq!(results[1].matches[0].gaps, vec![(34, 35)]);
+
+        // too many tokens in between
+        let results = tree.search("Sula (Morus) x bassana", &options);
+        assert!(results.is_empty());
+        let options = SearchOptions {
+            max_gap: Some(2),
+            ..Default::default()
+        };
+        let results = tree.search("Sula (Morus) x bassana", &options);
+        assert_eq!(results[0].matches[0].gaps, vec![(6, 11), (13, 14)]);
+
+        // gaps are clamped to the maximum of the tree
+        tree.set_max_gap(1);
+        assert!(tree.search("Sula (Morus) x bassana", &options).is_empty());
+    }
+
+    /// The same 500 entries with skip-grams generated by the index strategy and by the query
+    /// strategy, and a text that holds each entry once with its second and once with its second
+    /// and last token skipped, so all entries are matched.
+    fn skip_gram_strategies() -> (HashMapSearchTree, HashMapSearchTree, String) {
+        let provenance = provenance("corpus");
+        let words = |i: usize| -> Vec<String> {
+            let mut words = vec![
+                format!("g{}", i % 13),
+                format!("s{}", i % 17),
+                format!("t{}", i % 5),
+                format!("u{}", i % 3),
+                format!("v{i}"),
+                format!("w{}", i % 7),
+            ];
+            words.truncate(3 + i % 4);
+            words
+        };
+        let entries: Vec<(String, String, Option<Arc<Provenance>>)> = (0..500)
+            .map(|i| {
+                (
+                    words(i).join(" "),
+                    format!("uri:{i}"),
+                    Some(provenance.clone()),
+                )
+            })
+            .collect();
+
+        let mut index = HashMapSearchTree::default();
+        index.load_with_provenance(entries.clone(), true, 2, 3, false, 0, 3);
+        let mut lazy = HashMapSearchTree::default();
+        lazy.load_with_provenance(entries, false, 0, 0, false, 0, 3);
+        lazy.enable_lazy_skip_grams(
+            "corpus",
+            SkipGramOptions {
+                min_length: 2,
+                max_skips: 3,
+            },
+        );
+
+        let text = (0..500)
+            .flat_map(|i| {
+                let words = words(i);
+                let mut once = words.clone();
+                once.remove(1);
+                let mut twice = once.clone();
+                twice.pop();
+                [once.join(" "), twice.join(" ")]
+            })
+            .join(" . ");
+        (index, lazy, text)
+    }
+
+    #[test]
+    fn test_lazy_skip_grams() {
+        let (index, lazy, text) = skip_gram_strategies();
+        let options = SearchOptions {
+            result_selection: Some(ResultSelection::All),
+            ..Default::default()
+        };
+        let search = |tree: &HashMapSearchTree| {
+            let mut results = tree.search(&text, &options);
+            for result in &mut results {
+                result.matches.sort_by(|a, b| a.cmp_entry(b));
+            }
+            results
+        };
+        let expected = search(&index);
+        assert!(expected
+            .iter()
+            .flat_map(|result| &result.matches)
+            .any(|mtch| mtch.match_type == MatchType::SkipGram));
+        assert_eq!(search(&lazy), expected);
+
+        // the index strategy inserts every skip-gram of every entry, while the query strategy
+        // leaves the tree at the entries themselves
+        let count = |tree: &HashMapSearchTree| {
+            let matches: usize = tree.nodes.iter().map(|node| node.matches.len()).sum();
+            (tree.nodes.len(), matches)
+        };
+        assert_eq!(count(&index), (5659, 6375));
+        assert_eq!(count(&lazy), (1485, 500));
+    }
+
+    /// Reports the latency of both skip-gram strategies, run with
+    /// `cargo test --release -- --ignored --nocapture bench_lazy_skip_grams`. The query strategy
+    /// walks past up to `max_skips` tree tokens at each position of the text.
+    #[test]
+    #[ignore]
+    fn bench_lazy_skip_grams() {
+        let (index, lazy, text) = skip_gram_strategies();
+        let options = SearchOptions {
+            result_selection: Some(ResultSelection::All),
+            ..Default::default()
+        };
+        for (strategy, tree) in [("index", &index), ("query", &lazy)] {
+            let start = std::time::Instant::now();
+            let results = tree.search(&text, &options);
+            println!(
+                "{strategy}: {} results in {:?}",
+                results.len(),
+                start.elapsed()
+            );
+        }
+    }
+
+    #[test]
+    fn test_skip_gram_policy() {
+        let provenance = provenance("corpus");
+        let entries: Vec<(String, String, Option<Arc<Provenance>>)> = [
+            ("Puffinus puffinus mauretanicus yelkouan", "uri:1"),
+            ("Sula leucogaster plotus", "uri:2"),
+            ("Sula plotus", "uri:3"),
+        ]
+        .into_iter()
+        .map(|(search_term, label)| {
+            (
+                search_term.to_string(),
+                label.to_string(),
+                Some(provenance.clone()),
+            )
+        })
+        .collect();
+        let policy = SkipGramPolicy {
+            positions: SkipGramPositions::Interior,
+            max_variants: Some(2),
+            drop_collisions: true,
+        };
+
+        let mut index = HashMapSearchTree::default();
+        index.set_skip_gram_policy("corpus", policy);
+        let summary = index.load_with_provenance(entries.clone(), true, 2, 2, false, 0, 3);
+        assert_eq!(
+            summary,
+            LoadSummary {
+                entries: 3,
+                skip_grams: 3,
+                capped_skip_grams: 1,
+                colliding_skip_grams: 0,
+                taxon_variants: 0,
+                dropped_rows: Vec::new(),
+            }
+        );
+        assert_eq!(
+            index.drop_colliding_skip_grams(),
+            HashMap::from([("corpus".to_string(), 1)])
+        );
+
+        let mut lazy = HashMapSearchTree::default();
+        lazy.set_skip_gram_policy("corpus", policy);
+        lazy.load_with_provenance(entries, false, 0, 0, false, 0, 3);
+        lazy.enable_lazy_skip_grams(
+            "corpus",
+            SkipGramOptions {
+                min_length: 2,
+                max_skips: 2,
+            },
+        );
+
+        let text =
+            "Puffinus mauretanicus yelkouan . Puffinus puffinus yelkouan . Puffinus yelkouan . \
+            Puffinus puffinus mauretanicus . Sula plotus";
+        let options = SearchOptions {
+            result_selection: Some(ResultSelection::All),
+            ..Default::default()
+        };
+        let skip_grams = |tree: &HashMapSearchTree| {
+            tree.search(text, &options)
+                .into_iter()
+                .flat_map(|result| result.matches)
+                .filter(|mtch| mtch.match_type == MatchType::SkipGram)
+                .map(|mtch| (mtch.match_label.to_string(), mtch.skipped))
+                .collect::<Vec<_>>()
+        };
+        // the last token is protected, the variant skipping both interior tokens is capped and
+        // the skip-gram "Sula plotus" collides with the full entry
+        let expected = vec![
+            ("uri:1".to_string(), vec![1]),
+            ("uri:1".to_string(), vec![2]),
+        ];
+        assert_eq!(skip_grams(&index), expected);
+        assert_eq!(skip_grams(&lazy), expected);
     }
 }
@@ -4,25 +4,33 @@ use std::io;
 use std::io::{BufRead, Read};
 use std::path::Path;
 use std::str::FromStr;
+use std::sync::Arc;
 
 use anyhow::anyhow;
 use anyhow::Context;
-use csv::{ReaderBuilder, Trim};
+use csv::{ReaderBuilder, StringRecord, Trim};
 use flate2::bufread::GzDecoder;
 use glob::glob;
 use indicatif::{ProgressBar, ProgressStyle};
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
+use tokenizers::normalizers::replace::ReplacePattern;
 use tokenizers::normalizers::Sequence as NormalizerSequence;
-use tokenizers::normalizers::{Lowercase, NFKC};
+use tokenizers::normalizers::{Lowercase, Replace, StripAccents, NFC, NFD, NFKC, NFKD};
+use tokenizers::pre_tokenizers::bert::BertPreTokenizer;
+use tokenizers::pre_tokenizers::digits::Digits;
 use tokenizers::pre_tokenizers::punctuation::Punctuation;
 use tokenizers::pre_tokenizers::sequence::Sequence as PreTokenizerSequence;
-use tokenizers::pre_tokenizers::whitespace::Whitespace;
+use tokenizers::pre_tokenizers::unicode_scripts::UnicodeScripts;
+use tokenizers::pre_tokenizers::whitespace::{Whitespace, WhitespaceSplit};
 use tokenizers::{
-    Normalizer, NormalizerWrapper, OffsetReferential, OffsetType, PreTokenizedString, PreTokenizer,
-    PreTokenizerWrapper, SplitDelimiterBehavior,
+    NormalizedString, Normalizer, NormalizerWrapper, OffsetReferential, OffsetType,
+    PreTokenizedString, PreTokenizer, PreTokenizerWrapper, SplitDelimiterBehavior,
 };
 
+use crate::label_template::{CompiledLabelTemplate, LabelTemplate};
+use crate::row_filter::{CompiledRowFilter, DroppedRows, RowFilter};
+
 #[derive(Clone, Serialize, Deserialize, Debug, Default)]
 pub struct CorpusFormat {
     /// The comment character. Defaults to b'#'.
@@ -52,6 +60,59 @@ pub struct CorpusFormat {
     /// The label pattern string, i.e. the part of the label_format_string that is replaced with
     /// the label. Defaults to '{}'.
     pub label_format_pattern: Option<String>,
+    /// If given, builds the label from any columns instead of `label_column_idx`, see
+    /// `LabelTemplate`. Cannot be combined with `label_format_string`.
+    pub label_template: Option<String>,
+    /// Replace rules applied to the search terms of this corpus only, before they are tokenized.
+    /// Unlike the rules of the `[tokenizer]` section, these are not applied to queries.
+    pub search_term_replace: Option<Vec<ReplaceRule>>,
+    /// Additional columns that are attached to each entry as key/value metadata, see `Column`.
+    /// The key is the header of the column if the input has a header, its index otherwise.
+    pub metadata_columns: Option<Vec<Column>>,
+    /// Columns holding alternate names of the entry, which are inserted with the label of the row.
+    pub alternate_name_columns: Option<Vec<AlternateNameColumn>>,
+    /// Rows that do not satisfy all of these filters are dropped, see `RowFilter`.
+    pub row_filters: Option<Vec<RowFilter>>,
+}
+
+/// A column holding any number of alternate names of an entry, i.e. its synonyms.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct AlternateNameColumn {
+    pub column: Column,
+    /// The delimiter between the names. Defaults to '|'.
+    pub delimiter: Option<String>,
+    /// If true, the names are marked as synonyms of the search term, see `Provenance::synonym`.
+    /// Defaults to false.
+    pub mark_synonyms: Option<bool>,
+}
+
+/// A column of the input table, given either by its index or by its header. Headers require
+/// `has_header`.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Column {
+    Index(usize),
+    Header(String),
+}
+
+impl Column {
+    /// Returns the index and the name of the column.
+    pub fn resolve(&self, headers: Option<&StringRecord>) -> anyhow::Result<(usize, String)> {
+        match self {
+            Column::Index(idx) => {
+                let name = headers
+                    .and_then(|headers| headers.get(*idx))
+                    .map_or_else(|| idx.to_string(), String::from);
+                Ok((*idx, name))
+            }
+            Column::Header(header) => headers
+                .context("Columns can only be given by header if the input has a header")?
+                .iter()
+                .position(|name| name == header)
+                .map(|idx| (idx, header.clone()))
+                .with_context(|| format!("Unknown column '{header}'")),
+        }
+    }
 }
 
 pub struct RobustCorpusFormat {
@@ -82,6 +143,16 @@ pub struct RobustCorpusFormat {
     /// The label pattern string, i.e. the part of the label_format_string that is replaced with
     /// the label. Defaults to '{}'.
     pub label_format_pattern: String,
+    /// If given, builds the label from any columns instead of `label_column_idx`.
+    pub label_template: Option<LabelTemplate>,
+    /// Normalizes the search terms before they are tokenized, see `ReplaceRule`.
+    pub search_term_normalizer: Option<NormalizerWrapper>,
+    /// Additional columns that are attached to each entry as key/value metadata.
+    pub metadata_columns: Vec<Column>,
+    /// Columns holding alternate names of the entry.
+    pub alternate_name_columns: Vec<AlternateNameColumn>,
+    /// Rows that do not satisfy all of these filters are dropped.
+    pub row_filters: Vec<RowFilter>,
 }
 
 impl Default for RobustCorpusFormat {
@@ -99,6 +170,11 @@ impl Default for RobustCorpusFormat {
             label_column_idx: 1,
             label_format_string: None,
             label_format_pattern: String::from("{}"),
+            label_template: None,
+            search_term_normalizer: None,
+            metadata_columns: Vec::new(),
+            alternate_name_columns: Vec::new(),
+            row_filters: Vec::new(),
         }
     }
 }
@@ -131,6 +207,18 @@ impl TryFrom<CorpusFormat> for RobustCorpusFormat {
             label_format_pattern: format
                 .label_format_pattern
                 .unwrap_or(default.label_format_pattern),
+            label_template: format
+                .label_template
+                .as_deref()
+                .map(LabelTemplate::try_from)
+                .transpose()?,
+            search_term_normalizer: format
+                .search_term_replace
+                .map(|rules| replace_normalizer(&rules))
+                .transpose()?,
+            metadata_columns: format.metadata_columns.unwrap_or_default(),
+            alternate_name_columns: format.alternate_name_columns.unwrap_or_default(),
+            row_filters: format.row_filters.unwrap_or_default(),
         };
         if let Some(label_format_string) = &robust_corpus_format.label_format_string {
             if !label_format_string.contains(&robust_corpus_format.label_format_pattern) {
@@ -138,11 +226,35 @@ impl TryFrom<CorpusFormat> for RobustCorpusFormat {
                     "The label format string must contain the label format pattern"
                 ));
             }
+            if robust_corpus_format.label_template.is_some() {
+                return Err(anyhow!(
+                    "The label format string cannot be combined with a label template"
+                ));
+            }
         }
         Ok(robust_corpus_format)
     }
 }
 
+/// The non-empty values of the metadata columns of a row as `(key, value)`. Shared by all names of
+/// the row, with values interned per file.
+pub type Metadata = Arc<[(Arc<str>, Arc<str>)]>;
+
+/// A search term and its label as read from a corpus file.
+#[derive(Debug, Clone)]
+pub struct CorpusRow {
+    pub search_term: String,
+    pub label: String,
+    /// The file the row was read from.
+    pub source: Arc<String>,
+    /// The (1-based) line number of the row in its source file.
+    pub row: u64,
+    /// The non-empty values of the metadata columns, see `Metadata`.
+    pub metadata: Metadata,
+    /// Whether the search term was read from an alternate-name column marked as synonyms.
+    pub synonym: bool,
+}
+
 pub fn read_lines(filename: &str) -> Vec<String> {
     let extension = match Path::new(filename).extension() {
         None => "",
@@ -165,7 +277,16 @@ pub fn read_lines(filename: &str) -> Vec<String> {
     }
 }
 
-pub fn read_csv(filename: &str, format: &CorpusFormat) -> anyhow::Result<Vec<(String, String)>> {
+pub fn read_csv(filename: &str, format: &CorpusFormat) -> anyhow::Result<Vec<CorpusRow>> {
+    read_csv_with_filter_counts(filename, format).map(|(rows, _)| rows)
+}
+
+/// Reads a corpus file like `read_csv`, also returning the number of rows dropped by each of the
+/// `row_filters` of the format. Rows are counted for the first filter they do not satisfy.
+pub fn read_csv_with_filter_counts(
+    filename: &str,
+    format: &CorpusFormat,
+) -> anyhow::Result<(Vec<CorpusRow>, Vec<usize>)> {
     let extension = match Path::new(filename).extension() {
         None => "",
         Some(ext) => ext.to_str().unwrap(),
@@ -193,8 +314,10 @@ pub fn read_csv(filename: &str, format: &CorpusFormat) -> anyhow::Result<Vec<(St
     let search_term_column_idx = format.search_term_column_idx;
     let label_column_idx = format.label_column_idx;
     let label_format_pattern = format.label_format_pattern;
+    let source = Arc::new(String::from(filename));
+    let skip_lines = format.skip_lines as u64;
 
-    let reader = ReaderBuilder::new()
+    let mut reader = ReaderBuilder::new()
         .comment(format.comment)
         .delimiter(format.delimiter)
         .double_quote(format.double_quote)
@@ -203,31 +326,150 @@ pub fn read_csv(filename: &str, format: &CorpusFormat) -> anyhow::Result<Vec<(St
         .quote(format.quote)
         .quoting(format.quoting)
         .trim(Trim::All)
-        .from_reader(buf_reader)
-        .records()
+        .from_reader(buf_reader);
+    let headers = if format.has_header {
+        Some(reader.headers().context("Failed to read header")?.clone())
+    } else {
+        None
+    };
+    let metadata_columns: Vec<(usize, Arc<str>)> = format
+        .metadata_columns
+        .iter()
+        .map(|column| {
+            column
+                .resolve(headers.as_ref())
+                .map(|(idx, name)| (idx, Arc::from(name)))
+        })
+        .collect::<anyhow::Result<_>>()
+        .context(format!("Invalid metadata column in {filename}"))?;
+    let alternate_name_columns: Vec<(usize, String, bool)> = format
+        .alternate_name_columns
+        .iter()
+        .map(|alternate_names| {
+            alternate_names
+                .column
+                .resolve(headers.as_ref())
+                .map(|(idx, _)| {
+                    (
+                        idx,
+                        alternate_names
+                            .delimiter
+                            .clone()
+                            .unwrap_or_else(|| String::from("|")),
+                        alternate_names.mark_synonyms.unwrap_or(false),
+                    )
+                })
+        })
+        .collect::<anyhow::Result<_>>()
+        .context(format!("Invalid alternate name column in {filename}"))?;
+    let row_filters: Vec<CompiledRowFilter> = format
+        .row_filters
+        .iter()
+        .map(|filter| CompiledRowFilter::new(filter, headers.as_ref()))
+        .collect::<anyhow::Result<_>>()
+        .context(format!("Invalid row filter in {filename}"))?;
+    let label_template: Option<CompiledLabelTemplate> = format
+        .label_template
+        .as_ref()
+        .map(|template| CompiledLabelTemplate::new(template, headers.as_ref()))
+        .transpose()
+        .context(format!("Invalid label template in {filename}"))?;
+    let mut dropped: Vec<usize> = vec![0; row_filters.len()];
+    // metadata values repeat across rows, i.e. ranks or statuses, so each is only stored once
+    let mut values: HashSet<Arc<str>> = HashSet::new();
+    let mut intern = |value: &str| -> Arc<str> {
+        if let Some(value) = values.get(value) {
+            return value.clone();
+        }
+        let value: Arc<str> = Arc::from(value);
+        values.insert(value.clone());
+        value
+    };
+    let no_metadata: Metadata = Arc::default();
+
+    let reader = reader
+        .into_records()
         .filter_map(std::result::Result::ok)
-        .filter_map(|row| {
+        .flat_map(|row| {
             if row.is_empty() {
-                return None;
+                return Vec::new();
+            }
+            if let Some(idx) = row_filters.iter().position(|filter| !filter.accepts(&row)) {
+                dropped[idx] += 1;
+                return Vec::new();
             }
-            match (row.get(search_term_column_idx), row.get(label_column_idx)) {
-                (None, None) | (None, _) | (_, None) => None,
+            let line = row.position().map_or(0, |position| position.line()) + skip_lines;
+            let label = match &label_template {
+                Some(template) => template.render(&row),
+                None => row.get(label_column_idx).map(String::from),
+            };
+            match (row.get(search_term_column_idx), label) {
+                (None, None) | (None, _) | (_, None) => Vec::new(),
                 (Some(search_term), Some(label)) => {
-                    Some((String::from(search_term), String::from(label)))
+                    let metadata: Metadata = if metadata_columns.is_empty() {
+                        no_metadata.clone()
+                    } else {
+                        metadata_columns
+                            .iter()
+                            .filter_map(|(idx, key)| {
+                                row.get(*idx)
+                                    .filter(|value| !value.is_empty())
+                                    .map(|value| (key.clone(), intern(value)))
+                            })
+                            .collect()
+                    };
+                    let alternate_names =
+                        alternate_name_columns
+                            .iter()
+                            .flat_map(|(idx, delimiter, synonym)| {
+                                row.get(*idx)
+                                    .into_iter()
+                                    .flat_map(move |names| names.split(delimiter.as_str()))
+                                    .map(str::trim)
+                                    .filter(|name| !name.is_empty())
+                                    .map(|name| (name, *synonym))
+                            });
+                    std::iter::once((search_term, false))
+                        .chain(alternate_names)
+                        .map(|(search_term, synonym)| {
+                            (
+                                String::from(search_term),
+                                label.clone(),
+                                line,
+                                metadata.clone(),
+                                synonym,
+                            )
+                        })
+                        .collect()
                 }
             }
         })
-        .map(|(search_term, label)| {
-            if let Some(format_string) = format.label_format_string.as_ref() {
-                return (
-                    search_term,
-                    format_string.replace(&label_format_pattern, &label),
-                );
-            }
-            (search_term, label)
+        .map(|(search_term, label, line, metadata, synonym)| {
+            let search_term = match format.search_term_normalizer.as_ref() {
+                Some(normalizer) => {
+                    let mut normalized = NormalizedString::from(search_term.as_str());
+                    normalizer.normalize(&mut normalized)?;
+                    String::from(normalized.get())
+                }
+                None => search_term,
+            };
+            let label = match format.label_format_string.as_ref() {
+                Some(format_string) => format_string.replace(&label_format_pattern, &label),
+                None => label,
+            };
+            Ok(CorpusRow {
+                search_term,
+                label,
+                source: source.clone(),
+                row: line,
+                metadata,
+                synonym,
+            })
         })
-        .collect::<Vec<(String, String)>>();
-    Ok(reader)
+        .collect::<tokenizers::Result<Vec<CorpusRow>>>()
+        .map_err(|e| anyhow!(e))
+        .context(format!("Failed to normalize search terms of {filename}"))?;
+    Ok((reader, dropped))
 }
 
 #[must_use]
@@ -246,6 +488,49 @@ pub const SPLIT_PATTERN: &[char; 10] = &[' ', '.', ',', ':', ';', '-', '_', '"',
 
 pub type TokensAndOffsets = (Vec<String>, Vec<(usize, usize)>);
 
+/// The unit of the offsets reported by a search. `Tokenizer::tokenize` reports characters, Java
+/// and JavaScript strings count UTF-16 code units.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OffsetUnit {
+    #[default]
+    Char,
+    Utf16,
+    Byte,
+}
+
+impl OffsetUnit {
+    /// The offset of each character of the text in this unit, followed by the length of the text.
+    #[must_use]
+    pub fn boundaries(self, text: &str) -> Vec<usize> {
+        let mut boundaries = Vec::with_capacity(text.len() + 1);
+        let mut offset = 0;
+        for c in text.chars() {
+            boundaries.push(offset);
+            offset += match self {
+                OffsetUnit::Char => 1,
+                OffsetUnit::Utf16 => c.len_utf16(),
+                OffsetUnit::Byte => c.len_utf8(),
+            };
+        }
+        boundaries.push(offset);
+        boundaries
+    }
+
+    /// Converts character offsets, as reported by `Tokenizer::tokenize`, into this unit.
+    #[must_use]
+    pub fn convert(self, text: &str, offsets: Vec<(usize, usize)>) -> Vec<(usize, usize)> {
+        if self == OffsetUnit::Char {
+            return offsets;
+        }
+        let boundaries = self.boundaries(text);
+        offsets
+            .into_iter()
+            .map(|(begin, end)| (boundaries[begin], boundaries[end]))
+            .collect()
+    }
+}
+
 #[must_use]
 pub fn split_with_indices(s: &str) -> TokensAndOffsets {
     let indices = s.match_indices(SPLIT_PATTERN).collect::<Vec<_>>();
@@ -280,12 +565,14 @@ fn _push_slice(
     }
 }
 
+/// Reads all corpus files in parallel. Returns the rows and the number of rows dropped by each of
+/// the `row_filters` of the format, as `(filter, count)`.
 pub fn parse_files(
     files: &Vec<String>,
     pb: Option<&ProgressBar>,
     format: &Option<CorpusFormat>,
     filter_list: &Option<Vec<String>>,
-) -> anyhow::Result<Vec<(String, String)>> {
+) -> anyhow::Result<(Vec<CorpusRow>, DroppedRows)> {
     let format: CorpusFormat = match format {
         None => CorpusFormat::default(),
         Some(format) => format.clone(),
@@ -296,26 +583,180 @@ pub fn parse_files(
             .map(|s| s.to_lowercase())
             .collect::<HashSet<String>>()
     });
-    let parsed_files: Result<Vec<Vec<(String, String)>>, anyhow::Error> = files
+    let parsed_files = files
         .par_iter()
         .map(|file| {
-            let pairs = read_csv(file, &format)?;
+            let pairs = read_csv_with_filter_counts(file, &format)?;
             if let Some(pb) = pb {
                 pb.inc(1);
             }
             Ok(pairs)
         })
-        .collect();
-    Ok(parsed_files?
-        .into_iter()
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let mut dropped: DroppedRows = format
+        .row_filters
+        .iter()
         .flatten()
-        .filter(|(search_term, _)| {
-            filter_list.is_empty() || !filter_list.contains(&search_term.to_lowercase())
-        })
-        .collect::<Vec<(String, String)>>())
+        .map(|filter| (filter.to_string(), 0))
+        .collect();
+    let mut rows = Vec::new();
+    for (file_rows, file_dropped) in parsed_files {
+        rows.extend(file_rows.into_iter().filter(|row| {
+            filter_list.is_empty() || !filter_list.contains(&row.search_term.to_lowercase())
+        }));
+        for ((_, count), file_count) in dropped.iter_mut().zip(file_dropped) {
+            *count += file_count;
+        }
+    }
+    Ok((rows, dropped))
+}
+
+/// Replaces all occurrences of `pattern` with `content`. The pattern is a literal string unless
+/// `regex` is set.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct ReplaceRule {
+    pub pattern: String,
+    pub content: String,
+    pub regex: Option<bool>,
+}
+
+impl TryFrom<&ReplaceRule> for Replace {
+    type Error = anyhow::Error;
+
+    fn try_from(rule: &ReplaceRule) -> Result<Self, Self::Error> {
+        let pattern = if rule.regex.unwrap_or(false) {
+            ReplacePattern::Regex(rule.pattern.clone())
+        } else {
+            ReplacePattern::String(rule.pattern.clone())
+        };
+        Replace::new(pattern, rule.content.as_str())
+            .map_err(|e| anyhow!(e))
+            .context(format!("Invalid replace pattern '{}'", rule.pattern))
+    }
+}
+
+fn replace_normalizer(rules: &[ReplaceRule]) -> anyhow::Result<NormalizerWrapper> {
+    Ok(NormalizerWrapper::Sequence(NormalizerSequence::new(
+        rules
+            .iter()
+            .map(|rule| Ok(NormalizerWrapper::Replace(Replace::try_from(rule)?)))
+            .collect::<anyhow::Result<Vec<NormalizerWrapper>>>()?,
+    )))
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum UnicodeNormalization {
+    None,
+    NFC,
+    NFD,
+    NFKC,
+    NFKD,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum PreTokenizerKind {
+    /// Splits on punctuation and removes it.
+    Punctuation,
+    /// Splits into word characters (`\w+`) and runs of other non-whitespace characters.
+    Whitespace,
+    /// Splits on whitespace only.
+    WhitespaceSplit,
+    /// Splits digits from other characters.
+    Digits,
+    /// Splits where the unicode script changes.
+    UnicodeScripts,
+    /// Splits on whitespace and punctuation, keeping the punctuation as separate tokens.
+    Bert,
+}
+
+impl From<PreTokenizerKind> for PreTokenizerWrapper {
+    fn from(kind: PreTokenizerKind) -> Self {
+        match kind {
+            PreTokenizerKind::Punctuation => {
+                PreTokenizerWrapper::Punctuation(Punctuation::new(SplitDelimiterBehavior::Removed))
+            }
+            PreTokenizerKind::Whitespace => PreTokenizerWrapper::Whitespace(Whitespace),
+            PreTokenizerKind::WhitespaceSplit => {
+                PreTokenizerWrapper::WhitespaceSplit(WhitespaceSplit)
+            }
+            PreTokenizerKind::Digits => PreTokenizerWrapper::Digits(Digits::new(false)),
+            PreTokenizerKind::UnicodeScripts => {
+                PreTokenizerWrapper::UnicodeScripts(UnicodeScripts::new())
+            }
+            PreTokenizerKind::Bert => PreTokenizerWrapper::BertPreTokenizer(BertPreTokenizer),
+        }
+    }
+}
+
+/// The `[tokenizer]` section of the configuration. The same tokenizer is used for all corpora and
+/// for the queries, as all of them are matched against the same tree.
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct TokenizerConfig {
+    /// If given, the normalizer and pre-tokenizer of this HF `tokenizer.json` are used instead of
+    /// the ones configured below. If the file lacks either of them, the configured one is used.
+    pub path: Option<String>,
+    /// Replace rules, applied in order before all other normalizers.
+    pub replace: Option<Vec<ReplaceRule>>,
+    /// Defaults to true.
+    pub lowercase: Option<bool>,
+    /// Removes combining marks after decomposing the text. Defaults to false.
+    pub strip_accents: Option<bool>,
+    /// Defaults to NFKC.
+    pub unicode_normalization: Option<UnicodeNormalization>,
+    /// Applied in order. Defaults to `["Punctuation", "Whitespace"]`.
+    pub pre_tokenizers: Option<Vec<PreTokenizerKind>>,
+}
+
+impl TokenizerConfig {
+    pub fn build(&self) -> anyhow::Result<Tokenizer> {
+        let mut normalizers = Vec::new();
+        for rule in self.replace.iter().flatten() {
+            normalizers.push(NormalizerWrapper::Replace(Replace::try_from(rule)?));
+        }
+        if self.lowercase.unwrap_or(true) {
+            normalizers.push(NormalizerWrapper::Lowercase(Lowercase));
+        }
+        if self.strip_accents.unwrap_or(false) {
+            normalizers.push(NormalizerWrapper::NFD(NFD));
+            normalizers.push(NormalizerWrapper::StripAccents(StripAccents));
+        }
+        match self
+            .unicode_normalization
+            .unwrap_or(UnicodeNormalization::NFKC)
+        {
+            UnicodeNormalization::None => {}
+            UnicodeNormalization::NFC => normalizers.push(NormalizerWrapper::NFC(NFC)),
+            UnicodeNormalization::NFD => normalizers.push(NormalizerWrapper::NFD(NFD)),
+            UnicodeNormalization::NFKC => normalizers.push(NormalizerWrapper::NFKC(NFKC)),
+            UnicodeNormalization::NFKD => normalizers.push(NormalizerWrapper::NFKD(NFKD)),
+        }
+        let pre_tokenizers = self
+            .pre_tokenizers
+            .clone()
+            .unwrap_or_else(|| vec![PreTokenizerKind::Punctuation, PreTokenizerKind::Whitespace]);
+        let mut tokenizer = Tokenizer {
+            normalizer: NormalizerWrapper::Sequence(NormalizerSequence::new(normalizers)),
+            pre_tokenizer: PreTokenizerWrapper::Sequence(PreTokenizerSequence::new(
+                pre_tokenizers.into_iter().map(Into::into).collect(),
+            )),
+        };
+
+        if let Some(path) = &self.path {
+            let pretrained = tokenizers::Tokenizer::from_file(path)
+                .map_err(|e| anyhow!(e))
+                .context(format!("Could not load tokenizer from {path}"))?;
+            if let Some(normalizer) = pretrained.get_normalizer() {
+                tokenizer.normalizer = normalizer.clone();
+            }
+            if let Some(pre_tokenizer) = pretrained.get_pre_tokenizer() {
+                tokenizer.pre_tokenizer = pre_tokenizer.clone();
+            }
+        }
+        Ok(tokenizer)
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Tokenizer {
     normalizer: NormalizerWrapper,
     pre_tokenizer: PreTokenizerWrapper,
@@ -359,35 +800,40 @@ impl Tokenizer {
 
 impl Default for Tokenizer {
     fn default() -> Tokenizer {
-        Tokenizer {
-            normalizer: NormalizerWrapper::Sequence(NormalizerSequence::new(vec![
-                NormalizerWrapper::Lowercase(Lowercase),
-                NormalizerWrapper::NFKC(NFKC),
-            ])),
-            pre_tokenizer: PreTokenizerWrapper::Sequence(PreTokenizerSequence::new(vec![
-                PreTokenizerWrapper::Punctuation(Punctuation::new(SplitDelimiterBehavior::Removed)),
-                PreTokenizerWrapper::Whitespace(Whitespace),
-            ])),
-        }
+        TokenizerConfig::default()
+            .build()
+            .expect("The default tokenizer configuration is valid")
     }
 }
 
-pub fn create_skip_grams(
-    items: Vec<Vec<String>>,
-    max_skips: i32,
-    min_length: i32,
-) -> Vec<Vec<String>> {
-    if max_skips > 0 && items.iter().all(|item| item.len() > min_length as usize) {
+/// A skip-gram key together with the (ascending) positions of the entry tokens that were skipped.
+pub type SkipGram = (Vec<String>, Vec<usize>);
+
+pub fn create_skip_grams(items: Vec<SkipGram>, max_skips: i32, min_length: i32) -> Vec<SkipGram> {
+    if max_skips > 0
+        && items
+            .iter()
+            .all(|(item, _)| item.len() > min_length as usize)
+    {
         let mut deleted = Vec::new();
-        for item in items {
-            let item = item.clone();
+        for (item, skipped) in items {
             let mut d: Vec<String> = Vec::new();
             let l = item.len();
             for i in 1..l {
                 d.clear();
                 d.extend_from_slice(&item[..i]);
                 d.extend_from_slice(&item[i + 1..]);
-                deleted.push(d.clone());
+                // map the position in the item back to the position in the entry
+                let mut position = i;
+                for idx in &skipped {
+                    if *idx <= position {
+                        position += 1;
+                    }
+                }
+                let mut s = skipped.clone();
+                s.push(position);
+                s.sort_unstable();
+                deleted.push((d.clone(), s));
             }
         }
         deleted.append(&mut create_skip_grams(
@@ -406,3 +852,329 @@ pub fn parse_optional<I: FromStr>(string: &Option<String>) -> Option<I> {
         .as_ref()
         .and_then(|s| s.parse::<I>().map_or(None, |val| Some(val)))
 }
+
+/// A file in the temporary directory for tests, removed again when dropped.
+#[cfg(test)]
+pub(crate) struct TempFile(std::path::PathBuf);
+
+#[cfg(test)]
+impl TempFile {
+    /// Writes `content` to a file named `name`, prefixed to be unique per test process.
+    pub(crate) fn new(name: &str, content: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("gazetteer-{}-{name}", std::process::id()));
+        std::fs::write(&path, content).unwrap();
+        TempFile(path)
+    }
+
+    pub(crate) fn path(&self) -> &str {
+        self.0.to_str().unwrap()
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// A directory in the temporary directory for tests, removed with its contents when dropped.
+#[cfg(test)]
+pub(crate) struct TempDir(std::path::PathBuf);
+
+#[cfg(test)]
+impl TempDir {
+    /// Creates a directory named `name`, prefixed to be unique per test process.
+    pub(crate) fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("gazetteer-{}-{name}", std::process::id()));
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::row_filter::Predicate;
+
+    #[test]
+    fn test_offset_units() {
+        let text = "Die 𝔖ula ölt";
+        let (_, offsets) = Tokenizer::default().tokenize(text);
+        assert_eq!(offsets, vec![(0, 3), (4, 8), (9, 12)]);
+        assert_eq!(
+            OffsetUnit::Utf16.convert(text, offsets.clone()),
+            vec![(0, 3), (4, 9), (10, 13)]
+        );
+        let offsets = OffsetUnit::Byte.convert(text, offsets);
+        assert_eq!(offsets, vec![(0, 3), (4, 11), (12, 16)]);
+        assert_eq!(&text[offsets[1].0..offsets[1].1], "𝔖ula");
+    }
+
+    #[test]
+    fn test_create_skip_grams() {
+        let entry: Vec<String> = ["a", "b", "c", "d"].map(String::from).to_vec();
+        let mut skip_grams = create_skip_grams(vec![(entry, Vec::new())], 2, 1);
+        skip_grams.sort();
+        skip_grams.dedup();
+        let skip_grams: Vec<(String, Vec<usize>)> = skip_grams
+            .into_iter()
+            .map(|(key, skipped)| (key.join(" "), skipped))
+            .collect();
+        assert_eq!(
+            skip_grams,
+            vec![
+                ("a b".to_string(), vec![2, 3]),
+                ("a b c".to_string(), vec![3]),
+                ("a b d".to_string(), vec![2]),
+                ("a c".to_string(), vec![1, 3]),
+                ("a c d".to_string(), vec![1]),
+                ("a d".to_string(), vec![1, 2]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tokenizer_config() {
+        let (tokens, _) = Tokenizer::default().tokenize("Größe (Puffinus) puffinus");
+        assert_eq!(tokens, vec!["größe", "puffinus", "puffinus"]);
+
+        let tokenizer: TokenizerConfig = toml::from_str(
+            r#"
+            lowercase = false
+            strip_accents = true
+            unicode_normalization = "NFC"
+            replace = [{ pattern = "ß", content = "ss" }]
+            pre_tokenizers = ["WhitespaceSplit"]
+            "#,
+        )
+        .unwrap();
+        let (tokens, offsets) = tokenizer.build().unwrap().tokenize("Größe (Puffinus)");
+        assert_eq!(tokens, vec!["Grosse", "(Puffinus)"]);
+        assert_eq!(offsets, vec![(0, 5), (6, 16)]);
+    }
+
+    #[test]
+    fn test_search_term_replace() {
+        let file = TempFile::new("corpus.tsv", "Salix × rubens\turi:salix\n");
+        let format = CorpusFormat {
+            search_term_replace: Some(vec![ReplaceRule {
+                pattern: String::from("× "),
+                content: String::new(),
+                regex: None,
+            }]),
+            ..Default::default()
+        };
+        let rows = read_csv(file.path(), &format).unwrap();
+        assert_eq!(rows[0].search_term, "Salix rubens");
+        assert_eq!(rows[0].label, "uri:salix");
+    }
+
+    #[test]
+    fn test_metadata_columns() {
+        let file = TempFile::new(
+            "metadata.tsv",
+            "name\tid\trank\tstatus\tkingdom\n\
+            Puffinus puffinus\turi:puffinus\tSPECIES\tACCEPTED\tAnimalia\n\
+            Puffinus\turi:genus\tGENUS\t\tAnimalia\n",
+        );
+        let format = CorpusFormat {
+            has_header: Some(true),
+            metadata_columns: Some(vec![
+                Column::Header(String::from("rank")),
+                Column::Index(3),
+                Column::Header(String::from("kingdom")),
+            ]),
+            ..Default::default()
+        };
+        let rows = read_csv(file.path(), &format);
+        let unknown = read_csv(
+            file.path(),
+            &CorpusFormat {
+                has_header: Some(true),
+                metadata_columns: Some(vec![Column::Header(String::from("family"))]),
+                ..Default::default()
+            },
+        );
+
+        let rows = rows.unwrap();
+        let metadata = |row: &CorpusRow| {
+            row.metadata
+                .iter()
+                .map(|(key, value)| format!("{key}={value}"))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            metadata(&rows[0]),
+            vec!["rank=SPECIES", "status=ACCEPTED", "kingdom=Animalia"]
+        );
+        // empty values are omitted
+        assert_eq!(metadata(&rows[1]), vec!["rank=GENUS", "kingdom=Animalia"]);
+        // repeated values are shared
+        assert!(Arc::ptr_eq(&rows[0].metadata[2].1, &rows[1].metadata[1].1));
+        assert!(unknown.is_err());
+    }
+
+    #[test]
+    fn test_alternate_name_columns() {
+        let file = TempFile::new(
+            "synonyms.tsv",
+            "Morus bassanus\turi:gannet\tSula bassana | Pelecanus bassanus\tNorthern Gannet\n\
+            Sula leucogaster\turi:booby\t\tBrown Booby\n",
+        );
+        let format = CorpusFormat {
+            alternate_name_columns: Some(vec![
+                AlternateNameColumn {
+                    column: Column::Index(2),
+                    delimiter: None,
+                    mark_synonyms: Some(true),
+                },
+                AlternateNameColumn {
+                    column: Column::Index(3),
+                    delimiter: Some(String::from(";")),
+                    mark_synonyms: None,
+                },
+            ]),
+            ..Default::default()
+        };
+        let rows = read_csv(file.path(), &format);
+
+        let rows: Vec<(String, String, u64, bool)> = rows
+            .unwrap()
+            .into_iter()
+            .map(|row| (row.search_term, row.label, row.row, row.synonym))
+            .collect();
+        let row = |search_term: &str, label: &str, line: u64, synonym: bool| {
+            (search_term.to_string(), label.to_string(), line, synonym)
+        };
+        assert_eq!(
+            rows,
+            vec![
+                row("Morus bassanus", "uri:gannet", 1, false),
+                row("Sula bassana", "uri:gannet", 1, true),
+                row("Pelecanus bassanus", "uri:gannet", 1, true),
+                row("Northern Gannet", "uri:gannet", 1, false),
+                row("Sula leucogaster", "uri:booby", 2, false),
+                row("Brown Booby", "uri:booby", 2, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_row_filters() {
+        let file = TempFile::new(
+            "filters.tsv",
+            "name\tid\ttaxonomicStatus\tkingdom\n\
+            Puffinus puffinus\turi:puffinus\tACCEPTED\tAnimalia\n\
+            Procellaria puffinus\turi:procellaria\tSYNONYM\tAnimalia\n\
+            Puffinus\turi:puffinus-fungus\tACCEPTED\tFungi\n\
+            Sula\turi:sula\tACCEPTED\tAnimalia\n",
+        );
+        let format = CorpusFormat {
+            has_header: Some(true),
+            row_filters: Some(vec![
+                RowFilter::Column {
+                    column: Column::Header(String::from("taxonomicStatus")),
+                    predicate: Predicate::Equals(String::from("ACCEPTED")),
+                },
+                RowFilter::Column {
+                    column: Column::Header(String::from("kingdom")),
+                    predicate: Predicate::In(vec![String::from("Animalia")]),
+                },
+            ]),
+            ..Default::default()
+        };
+        let files = vec![String::from(file.path())];
+        let parsed = parse_files(&files, None, &Some(format), &None);
+
+        let (rows, dropped) = parsed.unwrap();
+        let labels: Vec<&str> = rows.iter().map(|row| row.label.as_str()).collect();
+        assert_eq!(labels, vec!["uri:puffinus", "uri:sula"]);
+        assert_eq!(
+            dropped,
+            vec![
+                (String::from(r#"taxonomicStatus == "ACCEPTED""#), 1),
+                (String::from(r#"kingdom in ["Animalia"]"#), 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_label_template() {
+        let file = TempFile::new(
+            "template.tsv",
+            "taxonKey\tcanonicalName\tacceptedKey\n\
+            4352320\tSula bassana\t\n\
+            \tPelecanus bassanus\t4352320\n\
+            \tSula\t\n",
+        );
+        let format = CorpusFormat {
+            has_header: Some(true),
+            search_term_column_idx: Some(1),
+            label_template: Some(String::from(
+                "https://www.gbif.org/species/{taxonKey|acceptedKey}",
+            )),
+            ..Default::default()
+        };
+        let rows = read_csv(file.path(), &format);
+
+        let rows: Vec<(String, String)> = rows
+            .unwrap()
+            .into_iter()
+            .map(|row| (row.search_term, row.label))
+            .collect();
+        // rows without any of the label columns are dropped
+        assert_eq!(
+            rows,
+            vec![
+                (
+                    String::from("Sula bassana"),
+                    String::from("https://www.gbif.org/species/4352320")
+                ),
+                (
+                    String::from("Pelecanus bassanus"),
+                    String::from("https://www.gbif.org/species/4352320")
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tokenizer_from_file() {
+        let file = TempFile::new(
+            "tokenizer.json",
+            r#"{
+                "version": "1.0",
+                "truncation": null,
+                "padding": null,
+                "added_tokens": [],
+                "normalizer": {"type": "Lowercase"},
+                "pre_tokenizer": null,
+                "post_processor": null,
+                "decoder": null,
+                "model": {"type": "WordLevel", "vocab": {"[UNK]": 0}, "unk_token": "[UNK]"}
+            }"#,
+        );
+        let config = TokenizerConfig {
+            path: Some(String::from(file.path())),
+            pre_tokenizers: Some(vec![PreTokenizerKind::WhitespaceSplit]),
+            ..Default::default()
+        };
+        let tokenizer = config.build();
+
+        // the normalizer is taken from the file, the missing pre-tokenizer from the config
+        let (tokens, _) = tokenizer.unwrap().tokenize("Ǆemal P. puffinus");
+        assert_eq!(tokens, vec!["ǆemal", "p.", "puffinus"]);
+    }
+}